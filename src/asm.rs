@@ -1,4 +1,8 @@
-use std::isize;
+use crate::memory_map::MemoryMap;
+use crate::symbol_table::Binding;
+use crate::token::Loc;
+
+use AsmKind::*;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AsmKind {
@@ -6,6 +10,55 @@ pub enum AsmKind {
     LABEL {
         l: String,
     },
+    // .text, .data, .rodata, .bss, .section name
+    SECTION {
+        name: String,
+    },
+    // .byte, .half, .word, .dword などのデータ
+    // width は一つの値のバイト数
+    DATA {
+        width: isize,
+        items: Vec<DataItem>,
+    },
+    // .ascii, .asciz, .string の文字列と .incbin で埋め込むファイルのバイト列
+    ASCII {
+        bytes: Vec<u8>,
+    },
+    // .space, .skip, .zero, .fill
+    // size バイトの value を repeat 回並べる
+    SPACE {
+        repeat: isize,
+        size: isize,
+        value: isize,
+    },
+    // .memory "ROM: 0x0000, 4K; RAM: 0x1000, 2K"
+    MEMORY {
+        map: MemoryMap,
+    },
+    // .globl, .global, .local, .extern, .weak
+    // .extern は他のファイルで定義されるシンボルを宣言するので Global と同じに扱う
+    BIND {
+        binding: Binding,
+        names: Vec<String>,
+    },
+    // .size name, size
+    SIZE {
+        name: String,
+        size: SymbolSize,
+    },
+    // .org addr
+    // GNU as と同じく addr はセクションの先頭からの位置で、そこまでを fill で埋める
+    ORG {
+        addr: isize,
+        fill: Option<isize>,
+    },
+    // .align, .balign, .p2align
+    // align はバイト数、詰めるバイト数が max を超える場合は何もしない
+    ALIGN {
+        align: isize,
+        fill: Option<isize>,
+        max: Option<isize>,
+    },
     LW {
         imm: isize,
         rs1: isize,
@@ -16,11 +69,34 @@ pub enum AsmKind {
         rs2: isize,
         rs1: isize,
     },
+    LUI {
+        imm: isize,
+        rd: isize,
+    },
+    AUIPC {
+        imm: isize,
+        rd: isize,
+    },
     ADDI {
         imm: isize,
         rs1: isize,
         rd: isize,
     },
+    ANDI {
+        imm: isize,
+        rs1: isize,
+        rd: isize,
+    },
+    ORI {
+        imm: isize,
+        rs1: isize,
+        rd: isize,
+    },
+    XORI {
+        imm: isize,
+        rs1: isize,
+        rd: isize,
+    },
     ADD {
         rs2: isize,
         rs1: isize,
@@ -132,9 +208,225 @@ pub enum AsmKind {
         rs1: isize,
         label: Option<String>,
     },
+    JAL {
+        imm: Option<isize>,
+        rd: isize,
+        label: Option<String>,
+    },
+    JALR {
+        imm: isize,
+        rs1: isize,
+        rd: isize,
+    },
+    // 複数の命令に展開される疑似命令
+    // li rd, imm
+    LI {
+        imm: isize,
+        rd: isize,
+    },
+    // la rd, label
+    LA {
+        rd: isize,
+        label: String,
+    },
+    // lw rd, label または lw rd, label(rs1)
+    // rs1 が 0 ならラベルのアドレスだけを使う
+    LWSYM {
+        rd: isize,
+        rs1: isize,
+        label: String,
+    },
+    // sw rs2, label, rt または sw rs2, label(rs1), rt
+    SWSYM {
+        rs2: isize,
+        rs1: isize,
+        rt: isize,
+        label: String,
+    },
+    // call label
+    CALL {
+        label: String,
+    },
+    // tail label
+    TAIL {
+        label: String,
+    },
+}
+
+impl AsmKind {
+    // 即値を持つ命令の即値を書き換える
+    pub fn set_imm(&mut self, v: isize) -> Result<(), String> {
+        match self {
+            Self::LW { imm, .. }
+            | Self::SW { imm, .. }
+            | Self::LUI { imm, .. }
+            | Self::AUIPC { imm, .. }
+            | Self::ADDI { imm, .. }
+            | Self::ANDI { imm, .. }
+            | Self::ORI { imm, .. }
+            | Self::XORI { imm, .. }
+            | Self::JALR { imm, .. }
+            | Self::SLTI { imm, .. }
+            | Self::SLTIU { imm, .. }
+            | Self::SLLI { imm, .. }
+            | Self::SRLI { imm, .. }
+            | Self::SRAI { imm, .. } => {
+                *imm = v;
+                Ok(())
+            }
+            x => Err(format!("AsmKind::set_imm: {:?} has no immediate", x)),
+        }
+    }
+}
+
+// .word などに書いた一つの値
+// label があればラベルのアドレスに value を足した値になる
+// sub があれば、さらに sub のアドレスを引く (同じセクションのラベルの差)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataItem {
+    pub value: isize,
+    pub label: Option<String>,
+    pub sub: Option<String>,
+}
+
+// .size に書いた大きさ
+// Diff は end - start で、end が None なら現在のアドレス (. - start)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SymbolSize {
+    Const(isize),
+    Diff { end: Option<String>, start: String },
+}
+
+// 再配置演算子
+// %hi(sym), %lo(sym), %pcrel_hi(sym), %pcrel_lo(label)
+// %hi(sym+4) のような加数は、シンボルの値に足してから上位と下位に分ける
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reloc {
+    Hi(String, isize),
+    Lo(String, isize),
+    PcrelHi(String, isize),
+    PcrelLo(String),
+}
+
+// 32bit のアドレス空間の大きさ
+// セクションや .space の領域はこの範囲に収まる必要がある
+pub const ADDRESS_SPACE: isize = 1 << 32;
+
+// 出力するセクション名を返す
+// .text.foo や .sdata のようなセクションは、リンカスクリプトと同じく
+// .text, .rodata, .data, .bss にまとめる
+pub fn output_section(name: &str) -> String {
+    let groups = [
+        (".text", &[".text"][..]),
+        (".rodata", &[".rodata", ".srodata"][..]),
+        (".data", &[".data", ".sdata"][..]),
+        (".bss", &[".bss", ".sbss"][..]),
+    ];
+    for (out, prefixes) in groups.iter() {
+        let matched = prefixes
+            .iter()
+            .any(|p| name == *p || name.starts_with(&format!("{}.", p)));
+        if matched {
+            return out.to_string();
+        }
+    }
+    name.to_string()
+}
+
+// 上位20bitを返す (下位12bitの符号拡張を考慮して丸める)
+pub fn hi20(v: isize) -> isize {
+    ((v + 0x800) >> 12) & 0xfffff
+}
+
+// 下位12bitを符号付きで返す
+pub fn lo12(v: isize) -> isize {
+    ((v & 0xfff) ^ 0x800) - 0x800
+}
+
+// li rd, imm を展開した命令列を返す
+// 12bit に収まる値は addi、それ以外は lui と addi に展開する
+// 下位12bitが 0 の場合は lui だけになる
+pub fn expand_li(imm: isize, rd: isize) -> Vec<AsmKind> {
+    // 32bit の値として符号拡張する
+    let imm = imm as i32 as isize;
+    if (-2048..2048).contains(&imm) {
+        return vec![ADDI { imm, rs1: 0, rd }];
+    }
+    let mut v = vec![LUI { imm: hi20(imm), rd }];
+    if lo12(imm) != 0 {
+        v.push(ADDI {
+            imm: lo12(imm),
+            rs1: rd,
+            rd,
+        });
+    }
+    v
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Asm {
     pub kind: AsmKind,
+    pub reloc: Option<Reloc>,
+    // ソースコード上の位置
+    pub loc: Loc,
+}
+
+impl Asm {
+    // Asmのコンストラクター
+    pub fn new(kind: AsmKind) -> Self {
+        Self {
+            kind,
+            reloc: None,
+            loc: Loc::default(),
+        }
+    }
+
+    // 再配置演算子を持つ Asm を作る
+    pub fn with_reloc(kind: AsmKind, reloc: Option<Reloc>) -> Self {
+        Self {
+            reloc,
+            ..Self::new(kind)
+        }
+    }
+
+    // 命令のバイト数を返す
+    // ラベルは命令を生成しないので 0 を返す
+    // 疑似命令は展開後の命令数から計算する
+    // .align, .org は置く位置で大きさが変わるので size_at で計算する
+    pub fn size(&self) -> isize {
+        match self.kind {
+            EOASM
+            | LABEL { .. }
+            | SECTION { .. }
+            | MEMORY { .. }
+            | BIND { .. }
+            | SIZE { .. }
+            | ALIGN { .. }
+            | ORG { .. } => 0,
+            LI { imm, rd } => 4 * expand_li(imm, rd).len() as isize,
+            LA { .. } | CALL { .. } | TAIL { .. } => 8,
+            // label(rs1) は rs1 を足す add が増える
+            LWSYM { rs1, .. } | SWSYM { rs1, .. } => 8 + 4 * (rs1 != 0) as isize,
+            DATA { width, ref items } => width * items.len() as isize,
+            ASCII { ref bytes } => bytes.len() as isize,
+            SPACE { repeat, size, .. } => repeat.saturating_mul(size),
+            _ => 4,
+        }
+    }
+
+    // 先頭アドレスが base のセクションの pc に置いたときのバイト数を返す
+    pub fn size_at(&self, pc: isize, base: isize) -> isize {
+        match self.kind {
+            ALIGN { align, max, .. } => {
+                let pad = (align - pc.rem_euclid(align)) % align;
+                match max {
+                    Some(max) if pad > max => 0,
+                    _ => pad,
+                }
+            }
+            // 後ろに戻る .org は make_label_table でエラーにする
+            ORG { addr, .. } => base.saturating_add(addr).saturating_sub(pc).max(0),
+            _ => self.size(),
+        }
+    }
 }
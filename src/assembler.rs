@@ -1,31 +1,92 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use crate::asm::{
-    Asm,
+    expand_li, hi20, lo12, Asm,
     AsmKind::{self, *},
+    DataItem, Reloc,
 };
-use crate::code_gen::gen_bin;
-use crate::inst::{Inst, InstType::*};
-use crate::label_table;
+use crate::code_gen::{gen_bytes, gen_words};
+use crate::inst::{
+    Inst,
+    InstType::{self, *},
+};
+use crate::label_table::{
+    is_local_label, locate, resolve_local_label, section_base, BSS_SECTION, TEXT_SECTION,
+};
+use crate::symbol_table::{RelocType, Relocation, SymbolTable};
 
 pub struct Assembler {
     a: Vec<Asm>,
     pos: usize,
-    lt: HashMap<String, isize>,
+    pc: isize,
+    lt: SymbolTable,
+    // 現在のセクションと、セクションごとの位置カウンタ
+    section: String,
+    pcs: HashMap<String, isize>,
+    // セクションの先頭アドレス
+    bases: HashMap<String, isize>,
+    // --base や .memory で配置した、同じアドレス空間にあるセクション
+    placed: HashSet<String>,
+    // %pcrel_hi を持つ auipc のセクションとアドレスから、シンボルと加数への対応表
+    pcrel_hi: HashMap<(String, isize), (String, isize)>,
+    // 現在位置までに定義された数字ラベルの定義回数
+    local_labels: HashMap<String, usize>,
+    // 疑似命令を展開した命令のうち、まだ返していない命令
+    pending: VecDeque<InstType>,
+    // 他のファイルで定義されるシンボルの参照
+    relocations: Vec<Relocation>,
 }
 
 impl Assembler {
     // Assemblerのコンストラクター
-    pub fn new(a: Vec<Asm>, lt: HashMap<String, isize>) -> Self {
+    pub fn new(a: Vec<Asm>, lt: SymbolTable) -> Self {
+        Self::with_bases(a, lt, HashMap::new())
+    }
+
+    // セクションの先頭アドレスを指定した Assembler を作る
+    // bases は make_label_table_with_bases に渡したものと同じにする
+    pub fn with_bases(a: Vec<Asm>, lt: SymbolTable, bases: HashMap<String, isize>) -> Self {
+        let placed = bases.keys().cloned().collect();
+        Self::with_layout(a, lt, bases, placed)
+    }
+
+    // 配置したセクション placed と、label_table::place_data_sections で
+    // データのアドレス空間に置いたセクションの先頭アドレス bases を持つ Assembler を作る
+    pub fn with_layout(
+        a: Vec<Asm>,
+        lt: SymbolTable,
+        bases: HashMap<String, isize>,
+        placed: HashSet<String>,
+    ) -> Self {
+        let pcrel_hi = Self::make_pcrel_table(&a, &bases);
         Self {
-            a: a,
+            a,
             pos: 0,
-            lt: lt,
+            pc: section_base(&bases, TEXT_SECTION),
+            lt,
+            section: TEXT_SECTION.to_string(),
+            pcs: HashMap::new(),
+            bases,
+            placed,
+            pcrel_hi,
+            local_labels: HashMap::new(),
+            pending: VecDeque::new(),
+            relocations: vec![],
         }
     }
 
-    pub fn get_pc(&self, label: &String) -> Option<&isize> {
-        self.lt.get(label)
+    pub fn get_pc(&self, label: &str) -> Option<isize> {
+        self.lt.value(label)
+    }
+
+    // ラベル表を返す
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.lt
+    }
+
+    // アセンブルした命令とデータにある、他のファイルで定義されるシンボルの参照を返す
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
     }
 
     // 次のアセンブラを取り出す関数
@@ -44,209 +105,885 @@ impl Assembler {
 
     // 次の１命令をアセンブルする関数
     pub fn assemble(&mut self) -> Result<Inst, String> {
-        let asm = self.next_asm()?;
+        // 疑似命令を展開した残りの命令を先に返す
+        if let Some(ty) = self.pending.pop_front() {
+            return Ok(Inst { ty });
+        }
+
+        // ラベル、セクションの切り替えと .memory, .globl, .size などは命令を生成しないので読み飛ばす
+        let mut asm = self.next_asm()?;
+        loop {
+            match &asm.kind {
+                LABEL { l } if is_local_label(l) => {
+                    *self.local_labels.entry(l.clone()).or_insert(0) += 1;
+                }
+                LABEL { .. } | MEMORY { .. } | BIND { .. } | SIZE { .. } => {}
+                SECTION { name } => self.switch_section(name),
+                _ => break,
+            }
+            asm = self.next_asm()?;
+        }
+
+        // エラーには命令の位置をつける
+        let size = asm.size_at(self.pc, section_base(&self.bases, &self.section));
+        let loc = asm.loc.clone();
+        // .bss は初期値を持たないので命令やデータは置けない
+        // 領域の確保は 0 で埋める場合だけ置ける
+        let reserves = matches!(
+            asm.kind,
+            EOASM
+                | ALIGN {
+                    fill: None | Some(0),
+                    ..
+                }
+                | ORG {
+                    fill: None | Some(0),
+                    ..
+                }
+                | SPACE { value: 0, .. }
+        );
+        if self.section == BSS_SECTION && !reserves {
+            return Err(format!(
+                "{}: Assembler::assemble: {:?} cannot be placed in .bss",
+                loc, asm.kind
+            ));
+        }
+        // 命令は 4 バイト境界に置く必要がある
+        let is_data = matches!(
+            asm.kind,
+            EOASM | AsmKind::DATA { .. } | ASCII { .. } | SPACE { .. } | ALIGN { .. } | ORG { .. }
+        );
+        if !is_data && self.pc % 4 != 0 {
+            return Err(format!(
+                "{}: Assembler::assemble: instruction at {:#x} is not aligned to 4 bytes",
+                loc, self.pc
+            ));
+        }
+        let relocations = self
+            .external_refs(&asm)
+            .map_err(|e| format!("{}: {}", loc, e))?;
+        let mut insts: VecDeque<InstType> = self
+            .expand_pseudo(asm)
+            .and_then(|a| a.into_iter().map(|asm| self.assemble_asm(asm)).collect())
+            .map_err(|e| format!("{}: {}", loc, e))?;
+        for (offset, ty, symbol, addend) in relocations {
+            self.relocations.push(Relocation {
+                section: self.section.clone(),
+                offset: self.pc + offset,
+                ty,
+                symbol,
+                addend,
+            });
+        }
+
+        self.pc += size;
+        let inst_type = insts.pop_front().unwrap_or(EOINST);
+        self.pending = insts;
+        Ok(Inst { ty: inst_type })
+    }
+
+    // セクションを切り替える関数
+    // 位置カウンタは切り替え前のセクションの続きから数える
+    fn switch_section(&mut self, name: &str) {
+        let prev = std::mem::replace(&mut self.section, name.to_string());
+        self.pcs.insert(prev, self.pc);
+        self.pc = *self
+            .pcs
+            .get(name)
+            .unwrap_or(&section_base(&self.bases, name));
+    }
+
+    // 疑似命令を基本命令の列に展開する関数
+    // 基本命令はそのまま返す
+    fn expand_pseudo(&self, asm: Asm) -> Result<Vec<Asm>, String> {
+        let kinds = match &asm.kind {
+            LI { imm, rd } => expand_li(*imm, *rd),
+            // auipc rd, %pcrel_hi(label); addi rd, rd, %pcrel_lo(label)
+            LA { rd, label } => {
+                let (upper, lo) = self.expand_address(label, *rd)?;
+                vec![
+                    upper,
+                    ADDI {
+                        imm: lo,
+                        rs1: *rd,
+                        rd: *rd,
+                    },
+                ]
+            }
+            // auipc rd, %pcrel_hi(label); lw rd, %pcrel_lo(label)(rd)
+            // label(rs1) は auipc の後に add rd, rd, rs1 を挟む
+            LWSYM { rd, rs1, label } => {
+                let (upper, lo) = self.expand_address(label, *rd)?;
+                let mut kinds = vec![upper];
+                kinds.extend(add_index(*rd, *rs1));
+                kinds.push(LW {
+                    imm: lo,
+                    rs1: *rd,
+                    rd: *rd,
+                });
+                kinds
+            }
+            // auipc rt, %pcrel_hi(label); sw rs2, %pcrel_lo(label)(rt)
+            // label(rs1) は auipc の後に add rt, rt, rs1 を挟む
+            SWSYM {
+                rs2,
+                rs1,
+                rt,
+                label,
+            } => {
+                let (upper, lo) = self.expand_address(label, *rt)?;
+                let mut kinds = vec![upper];
+                kinds.extend(add_index(*rt, *rs1));
+                kinds.push(SW {
+                    imm: lo,
+                    rs2: *rs2,
+                    rs1: *rt,
+                });
+                kinds
+            }
+            // auipc ra, %pcrel_hi(label); jalr ra, %pcrel_lo(label)(ra)
+            CALL { label } => {
+                let offset = self.pcrel_offset(label, self.pc)?;
+                vec![
+                    AUIPC {
+                        imm: hi20(offset),
+                        rd: 1,
+                    },
+                    JALR {
+                        imm: lo12(offset),
+                        rs1: 1,
+                        rd: 1,
+                    },
+                ]
+            }
+            // auipc t1, %pcrel_hi(label); jalr x0, %pcrel_lo(label)(t1)
+            TAIL { label } => {
+                let offset = self.pcrel_offset(label, self.pc)?;
+                vec![
+                    AUIPC {
+                        imm: hi20(offset),
+                        rd: 6,
+                    },
+                    JALR {
+                        imm: lo12(offset),
+                        rs1: 6,
+                        rd: 0,
+                    },
+                ]
+            }
+            _ => return Ok(vec![asm]),
+        };
+        Ok(kinds
+            .into_iter()
+            .map(|kind| Asm {
+                kind,
+                reloc: None,
+                loc: asm.loc.clone(),
+            })
+            .collect())
+    }
+
+    // Asm を命令形式に変換する関数
+    fn assemble_asm(&self, mut asm: Asm) -> Result<InstType, String> {
+        // 再配置演算子を解決して即値を書き換える
+        if let Some(reloc) = &asm.reloc {
+            let imm = self.resolve_reloc(reloc)?;
+            asm.kind.set_imm(imm)?;
+        }
+        check_imm(&asm.kind)?;
+
+        let size = asm.size_at(self.pc, section_base(&self.bases, &self.section));
         let inst_type = match asm.kind {
+            // 上位20bitの即値
+            LUI { imm, rd } => U {
+                imm,
+                rd,
+                opcode: 0b0110111,
+            },
+            AUIPC { imm, rd } => U {
+                imm,
+                rd,
+                opcode: 0b0010111,
+            },
             LW { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
+                imm,
+                rs1,
                 funct3: 0b010,
-                rd: rd,
+                rd,
                 opcode: 0b000011,
             },
             SW { imm, rs1, rs2 } => {
                 let imm_1 = (0b111111100000 & imm) >> 5;
                 let imm_2 = 0b000000011111 & imm;
                 S {
-                    imm_1: imm_1,
-                    rs2: rs2,
+                    imm_1,
+                    rs2,
                     funct3: 0b010,
-                    rs1: rs1,
-                    imm_2: imm_2,
+                    rs1,
+                    imm_2,
                     opcode: 0b0100011,
                 }
             }
             // 加減算
             ADDI { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
+                imm,
+                rs1,
                 funct3: 0b000,
-                rd: rd,
+                rd,
+                opcode: 0b0010011,
+            },
+            // 論理演算
+            ANDI { imm, rs1, rd } => I {
+                imm,
+                rs1,
+                funct3: 0b111,
+                rd,
+                opcode: 0b0010011,
+            },
+            ORI { imm, rs1, rd } => I {
+                imm,
+                rs1,
+                funct3: 0b110,
+                rd,
+                opcode: 0b0010011,
+            },
+            XORI { imm, rs1, rd } => I {
+                imm,
+                rs1,
+                funct3: 0b100,
+                rd,
                 opcode: 0b0010011,
             },
             ADD { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b000,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SUB { rs2, rs1, rd } => R {
                 funct7: 0b0100000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b000,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             // 論理演算
             AND { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b111,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             OR { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b110,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             XOR { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b100,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             // 比較演算
             SLT { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b010,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SLTU { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b011,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SLTI { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
+                imm,
+                rs1,
                 funct3: 0b010,
-                rd: rd,
+                rd,
                 opcode: 0b0010011,
             },
             SLTIU { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
+                imm,
+                rs1,
                 funct3: 0b011,
-                rd: rd,
+                rd,
                 opcode: 0b0010011,
             },
             SLL { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b001,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SRL { rs2, rs1, rd } => R {
                 funct7: 0b0000000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b101,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SRA { rs2, rs1, rd } => R {
                 funct7: 0b0100000,
-                rs2: rs2,
-                rs1: rs1,
+                rs2,
+                rs1,
                 funct3: 0b101,
-                rd: rd,
+                rd,
                 opcode: 0b0110011,
             },
             SLLI { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
-                funct3: 001,
-                rd: rd,
+                imm,
+                rs1,
+                funct3: 0b001,
+                rd,
                 opcode: 0b0010011,
             },
             SRLI { imm, rs1, rd } => I {
-                imm: imm,
-                rs1: rs1,
+                imm,
+                rs1,
                 funct3: 0b101,
-                rd: rd,
+                rd,
                 opcode: 0b0010011,
             },
             SRAI { imm, rs1, rd } => I {
                 imm: 0b010000000000 + imm,
-                rs1: rs1,
+                rs1,
                 funct3: 0b101,
-                rd: rd,
+                rd,
                 opcode: 0b0010011,
             },
-            // BEQ {
-            //     imm,
-            //     rs2,
-            //     rs1,
-            //     label,
-            // } => {
-            //     assemble_b(asm.kind)?;
-            // }
+            // 分岐
+            BEQ {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b000, imm, rs2, rs1, label)?,
+            BNE {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b001, imm, rs2, rs1, label)?,
+            BLT {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b100, imm, rs2, rs1, label)?,
+            BGE {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b101, imm, rs2, rs1, label)?,
+            BLTU {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b110, imm, rs2, rs1, label)?,
+            BGEU {
+                imm,
+                rs2,
+                rs1,
+                label,
+            } => self.assemble_b(0b111, imm, rs2, rs1, label)?,
+            // ジャンプ
+            JAL { imm, rd, label } => self.assemble_j(imm, rd, label)?,
+            JALR { imm, rs1, rd } => I {
+                imm,
+                rs1,
+                funct3: 0b000,
+                rd,
+                opcode: 0b1100111,
+            },
+            AsmKind::DATA { width, items } => InstType::DATA {
+                bytes: self.assemble_data(width, &items)?,
+            },
+            ASCII { bytes } => InstType::DATA { bytes },
+            // .bss の領域は出力しないので、バイト列を作らない
+            SPACE { .. } | ALIGN { .. } | ORG { .. } if self.section == BSS_SECTION => {
+                InstType::DATA { bytes: vec![] }
+            }
+            SPACE {
+                repeat,
+                size,
+                value,
+            } => InstType::DATA {
+                bytes: (value as i64).to_le_bytes()[..size as usize].repeat(repeat as usize),
+            },
+            ALIGN { fill, .. } | ORG { fill, .. } => InstType::DATA {
+                bytes: self.padding(size, fill),
+            },
             EOASM => EOINST,
             x => return Err(format!("Assembler::assemble: {:?} is not implemnted", x)),
         };
 
-        Ok(Inst { ty: inst_type })
+        Ok(inst_type)
+    }
+
+    // B形式の命令をアセンブルする関数
+    // 分岐先はラベルまたは現在の pc からのオフセットで指定する
+    fn assemble_b(
+        &self,
+        funct3: isize,
+        imm: Option<isize>,
+        rs2: isize,
+        rs1: isize,
+        label: Option<String>,
+    ) -> Result<InstType, String> {
+        let offset = match (imm, label) {
+            (Some(x), _) => x,
+            (None, Some(l)) => self.pcrel_offset(&l, self.pc)?,
+            (None, None) => {
+                return Err("Assembler::assemble_b: branch target is missing".to_string())
+            }
+        };
+        if !(-4096..4096).contains(&offset) || offset % 2 != 0 {
+            return Err(format!(
+                "Assembler::assemble_b: branch offset {} is out of range",
+                offset
+            ));
+        }
+
+        // imm_1 = imm[12|10:5], imm_2 = imm[4:1|11]
+        let imm_1 = (((offset >> 12) & 0b1) << 6) | ((offset >> 5) & 0b111111);
+        let imm_2 = (((offset >> 1) & 0b1111) << 1) | ((offset >> 11) & 0b1);
+        Ok(B {
+            imm_1,
+            rs2,
+            rs1,
+            funct3,
+            imm_2,
+            opcode: 0b1100011,
+        })
+    }
+
+    // J形式の命令をアセンブルする関数
+    // ジャンプ先はラベルまたは現在の pc からのオフセットで指定する
+    fn assemble_j(
+        &self,
+        imm: Option<isize>,
+        rd: isize,
+        label: Option<String>,
+    ) -> Result<InstType, String> {
+        let offset = match (imm, label) {
+            (Some(x), _) => x,
+            (None, Some(l)) => self.pcrel_offset(&l, self.pc)?,
+            (None, None) => return Err("Assembler::assemble_j: jump target is missing".to_string()),
+        };
+        if !(-(1 << 20)..(1 << 20)).contains(&offset) || offset % 2 != 0 {
+            return Err(format!(
+                "Assembler::assemble_j: jump offset {} is out of range",
+                offset
+            ));
+        }
+
+        // imm = imm[20|10:1|11|19:12]
+        let imm = (((offset >> 20) & 0b1) << 19)
+            | (((offset >> 1) & 0x3ff) << 9)
+            | (((offset >> 11) & 0b1) << 8)
+            | ((offset >> 12) & 0xff);
+        Ok(J {
+            imm,
+            rd,
+            opcode: 0b1101111,
+        })
+    }
+
+    // .word などの値をリトルエンディアンのバイト列に変換する関数
+    // 値は符号付きまたは符号なしの整数として width バイトに収まる必要がある
+    fn assemble_data(&self, width: isize, items: &[DataItem]) -> Result<Vec<u8>, String> {
+        let bits = width * 8;
+        let mut bytes = vec![];
+        for item in items.iter() {
+            let v = match (&item.label, &item.sub) {
+                (Some(l), Some(sub)) => self.label_difference(l, sub)?.wrapping_add(item.value),
+                // 他のファイルで定義されるシンボルは再配置で埋めるので 0 にする
+                (Some(l), None) if self.is_external(l)? => 0,
+                (Some(l), None) => self.lookup_label(l)?.wrapping_add(item.value),
+                _ => item.value,
+            };
+            if bits < 64 && !(-(1 << (bits - 1))..(1 << bits)).contains(&v) {
+                return Err(format!(
+                    "Assembler::assemble_data: value {} does not fit in {} bytes",
+                    v, width
+                ));
+            }
+            bytes.extend_from_slice(&(v as i64).to_le_bytes()[..width as usize]);
+        }
+        Ok(bytes)
+    }
+
+    // 同じセクションの二つのラベルのアドレスの差 (label - sub) を返す関数
+    // セクションの配置や他のファイルのシンボルによらず決まる場合だけ計算できる
+    fn label_difference(&self, label: &String, sub: &String) -> Result<isize, String> {
+        let mut sections = vec![];
+        for l in [label, sub].iter() {
+            if self.is_external(l)? {
+                return Err(format!(
+                    "Assembler::label_difference: external symbol {:?} cannot be subtracted",
+                    l
+                ));
+            }
+            self.lookup_label(l)?;
+            let key = self.label_key(l)?;
+            sections.push(self.lt.get(&key).and_then(|sym| sym.section.clone()));
+        }
+        if sections[0] != sections[1] {
+            return Err(format!(
+                "Assembler::label_difference: {:?} and {:?} are in different sections",
+                label, sub
+            ));
+        }
+        Ok(self.lookup_label(label)? - self.lookup_label(sub)?)
+    }
+
+    // .align, .org で詰める size バイトのバイト列を返す関数
+    // fill を省略した場合、.text では nop を、それ以外のセクションでは 0 を詰める
+    fn padding(&self, size: isize, fill: Option<isize>) -> Vec<u8> {
+        match fill {
+            Some(v) => vec![v as u8; size as usize],
+            None if self.section == TEXT_SECTION => {
+                // 命令の境界までは 0 で埋めてから nop (addi x0, x0, 0) を並べる
+                let mut bytes = vec![0; (size % 4) as usize];
+                for _ in 0..size / 4 {
+                    bytes.extend_from_slice(&0x13u32.to_le_bytes());
+                }
+                bytes
+            }
+            None => vec![0; size as usize],
+        }
+    }
+
+    // ラベルの参照をラベル表でのキーに変換する関数
+    // 1b, 1f のような数字ラベルの参照は現在位置から探す
+    fn label_key(&self, label: &str) -> Result<String, String> {
+        match resolve_local_label(label, &self.local_labels) {
+            Some(key) => key,
+            None => Ok(label.to_string()),
+        }
+    }
+
+    // 他のファイルで定義されるシンボルかを判定する関数
+    fn is_external(&self, label: &str) -> Result<bool, String> {
+        Ok(self.lt.is_external(&self.label_key(label)?))
+    }
+
+    // ラベルのアドレスを返す関数
+    // アドレスはラベルがあるセクションの先頭からの値
+    // 他のファイルで定義されるシンボルは、再配置で埋めるので 0 にする
+    fn lookup_label(&self, label: &String) -> Result<isize, String> {
+        let key = self.label_key(label)?;
+        if self.lt.is_external(&key) {
+            return Ok(0);
+        }
+        match self.get_pc(&key) {
+            Some(pc) => Ok(pc),
+            None => Err(format!(
+                "Assembler::lookup_label: label {:?} is not defined",
+                label
+            )),
+        }
+    }
+
+    // ラベルが現在のセクションにあるかを判定する関数
+    fn in_current_section(&self, label: &String) -> Result<bool, String> {
+        self.lookup_label(label)?;
+        let key = self.label_key(label)?;
+        let section = self.lt.get(&key).and_then(|sym| sym.section.as_ref());
+        Ok(section == Some(&self.section))
+    }
+
+    // ラベルのセクションと現在のセクションの両方の先頭アドレスが決まっているかを判定する関数
+    // --base や .memory で配置していないセクションの間の距離はわからない
+    fn is_placed_with(&self, label: &String) -> Result<bool, String> {
+        self.lookup_label(label)?;
+        let key = self.label_key(label)?;
+        let placed = self
+            .lt
+            .get(&key)
+            .and_then(|sym| sym.section.as_ref())
+            .is_some_and(|section| self.placed.contains(section));
+        Ok(placed && self.placed.contains(&self.section))
+    }
+
+    // pc からラベルまでのオフセットを返す関数
+    // ラベルの値と pc はどちらもセクションの先頭アドレスを足した値なので、
+    // 別のセクションのラベルも両方のセクションが配置されていれば差をとればよい
+    // 他のファイルで定義されるシンボルは、再配置で埋めるので 0 にする
+    fn pcrel_offset(&self, label: &String, pc: isize) -> Result<isize, String> {
+        if self.is_external(label)? {
+            return Ok(0);
+        }
+        if !self.in_current_section(label)? && !self.is_placed_with(label)? {
+            return Err(format!(
+                "Assembler::pcrel_offset: label {:?} is not in section {}, and the sections are not placed",
+                label, self.section
+            ));
+        }
+        Ok(self.lookup_label(label)? - pc)
+    }
+
+    // ラベルのアドレスを作る上位の命令と、下位12bitの値を返す関数
+    // 同じセクションのラベルと、配置が決まっている別のセクションのラベルは auipc で PC 相対にする
+    // 配置していない別のセクションのラベルは lui で絶対アドレスにする
+    fn expand_address(&self, label: &String, rd: isize) -> Result<(AsmKind, isize), String> {
+        if self.in_current_section(label)? || self.is_placed_with(label)? {
+            let offset = self.pcrel_offset(label, self.pc)?;
+            return Ok((
+                AUIPC {
+                    imm: hi20(offset),
+                    rd,
+                },
+                lo12(offset),
+            ));
+        }
+        let addr = self.lookup_label(label)?;
+        Ok((
+            LUI {
+                imm: hi20(addr),
+                rd,
+            },
+            lo12(addr),
+        ))
+    }
+
+    // 他のファイルで定義されるシンボルの参照を (命令の先頭からの位置, 種類, シンボル, 加数) で返す関数
+    // la, lw, sw のシンボルは別のセクションのラベルと同じく lui と下位12bitの組で参照する
+    fn external_refs(&self, asm: &Asm) -> Result<Vec<(isize, RelocType, String, isize)>, String> {
+        let mut refs = vec![];
+        // 下位12bitを I 形式と S 形式のどちらの即値に置くか
+        let (lo12, pcrel_lo12) = match asm.kind {
+            SW { .. } | SWSYM { .. } => (RelocType::Lo12S, RelocType::PcrelLo12S),
+            _ => (RelocType::Lo12I, RelocType::PcrelLo12I),
+        };
+        match &asm.reloc {
+            Some(Reloc::Hi(sym, addend)) if self.is_external(sym)? => {
+                refs.push((0, RelocType::Hi20, sym.clone(), *addend));
+            }
+            Some(Reloc::Lo(sym, addend)) if self.is_external(sym)? => {
+                refs.push((0, lo12, sym.clone(), *addend));
+            }
+            Some(Reloc::PcrelHi(sym, addend)) if self.is_external(sym)? => {
+                refs.push((0, RelocType::PcrelHi20, sym.clone(), *addend));
+            }
+            // %pcrel_lo は auipc のラベルを参照する
+            Some(Reloc::PcrelLo(label)) => {
+                let auipc_pc = self.lookup_label(label)?;
+                let external = self
+                    .pcrel_hi
+                    .get(&(self.section.clone(), auipc_pc))
+                    .is_some_and(|(sym, _)| self.lt.is_external(sym));
+                if external {
+                    refs.push((0, pcrel_lo12, self.label_key(label)?, 0));
+                }
+            }
+            _ => {}
+        }
+        match &asm.kind {
+            BEQ { label: Some(l), .. }
+            | BNE { label: Some(l), .. }
+            | BLT { label: Some(l), .. }
+            | BGE { label: Some(l), .. }
+            | BLTU { label: Some(l), .. }
+            | BGEU { label: Some(l), .. }
+                if self.is_external(l)? =>
+            {
+                refs.push((0, RelocType::Branch, l.clone(), 0))
+            }
+            JAL { label: Some(l), .. } if self.is_external(l)? => {
+                refs.push((0, RelocType::Jal, l.clone(), 0))
+            }
+            // 下位12bitはアドレスを使う最後の命令に置く
+            LA { label, .. } | LWSYM { label, .. } | SWSYM { label, .. }
+                if self.is_external(label)? =>
+            {
+                refs.push((0, RelocType::Hi20, label.clone(), 0));
+                refs.push((asm.size() - 4, lo12, label.clone(), 0));
+            }
+            CALL { label } | TAIL { label } if self.is_external(label)? => {
+                refs.push((0, RelocType::Call, label.clone(), 0));
+            }
+            AsmKind::DATA { width, items } => {
+                for (i, item) in items.iter().enumerate() {
+                    let l = match (&item.label, &item.sub) {
+                        (Some(l), None) if self.is_external(l)? => l,
+                        _ => continue,
+                    };
+                    let ty = match width {
+                        4 => RelocType::Abs32,
+                        8 => RelocType::Abs64,
+                        _ => {
+                            return Err(format!(
+                                "Assembler::external_refs: external symbol {:?} cannot be referenced by {}-byte data",
+                                l, width
+                            ))
+                        }
+                    };
+                    refs.push((width * i as isize, ty, l.clone(), item.value));
+                }
+            }
+            _ => {}
+        }
+        Ok(refs)
     }
 
-    // fn assemble_b(asm_kind: AsmKind) -> Result<Inst, String> {
-    //     let inst = match asm_kind {
-    //         BEQ {
-    //             imm,
-    //             rs2,
-    //             rs1,
-    //             label,
-    //         } => {
-    //             if let Some(pc) = imm {
-    //                 B {
-    //                     imm_1: pc,
-    //                     rs2: rs2,
-    //                     rs1: rs1,
-    //                     funct3: 0b000,
-    //                     imm_2: (pc & 0b11110) |
-    //                 }
-    //             }
-    //         }
-    //         _ => return Err(format!("{:?} is not B form", asm_kind)),
-    //     };
-    //     Ok(inst)
-    // }
+    // 再配置演算子の値を計算する関数
+    // %hi は %lo が符号拡張されることを考慮して 0x800 を足してから丸める
+    fn resolve_reloc(&self, reloc: &Reloc) -> Result<isize, String> {
+        let v = match reloc {
+            Reloc::Hi(sym, addend) => hi20(self.lookup_label(sym)? + self.addend(sym, *addend)?),
+            Reloc::Lo(sym, addend) => lo12(self.lookup_label(sym)? + self.addend(sym, *addend)?),
+            Reloc::PcrelHi(sym, addend) => {
+                hi20(self.pcrel_offset(sym, self.pc)? + self.addend(sym, *addend)?)
+            }
+            Reloc::PcrelLo(label) => {
+                // %pcrel_lo のラベルは %pcrel_hi を持つ auipc を指す
+                let auipc_pc = self.lookup_label(label)?;
+                let (sym, addend) = match self.pcrel_hi.get(&(self.section.clone(), auipc_pc)) {
+                    Some(entry) => entry,
+                    None => {
+                        return Err(format!(
+                            "Assembler::resolve_reloc: label {:?} does not point to auipc with %pcrel_hi",
+                            label
+                        ))
+                    }
+                };
+                lo12(self.pcrel_offset(sym, auipc_pc)? + self.addend(sym, *addend)?)
+            }
+        };
+        Ok(v)
+    }
+
+    // 再配置演算子の加数を返す関数
+    // 他のファイルで定義されるシンボルの加数は再配置に記録するので、命令には足さない
+    fn addend(&self, sym: &str, addend: isize) -> Result<isize, String> {
+        if self.is_external(sym)? {
+            return Ok(0);
+        }
+        Ok(addend)
+    }
+
+    // %pcrel_hi を持つ auipc のアドレスとシンボル、加数の対応表を作る関数
+    // 数字ラベルの参照は auipc の位置で解決したキーを記録する
+    fn make_pcrel_table(
+        a: &[Asm],
+        bases: &HashMap<String, isize>,
+    ) -> HashMap<(String, isize), (String, isize)> {
+        let mut table = HashMap::new();
+        let mut local_labels = HashMap::new();
+        // アドレスの範囲は collect_labels で確かめてある
+        for (asm, (section, pc)) in a.iter().zip(locate(a, bases).unwrap_or_default()) {
+            match (&asm.kind, &asm.reloc) {
+                (LABEL { l }, _) if is_local_label(l) => {
+                    *local_labels.entry(l.clone()).or_insert(0) += 1;
+                }
+                (AUIPC { .. }, Some(Reloc::PcrelHi(sym, addend))) => {
+                    let key = match resolve_local_label(sym, &local_labels) {
+                        Some(Ok(key)) => key,
+                        _ => sym.clone(),
+                    };
+                    table.insert((section, pc), (key, *addend));
+                }
+                _ => {}
+            }
+        }
+        table
+    }
 
     // 全ての文字列をアセンブラに変換
+    // セクションごとに (セクション名, 機械語) を返す
+    // 機械語はセクションのメモリの内容を 32bit ごとに一行にしたもの
+    // .text は常に先頭に置き、それ以外のセクションは現れた順に並べる
     #[allow(irrefutable_let_patterns)]
-    pub fn assemble_all(&mut self) -> Result<String, String> {
-        let mut src = "".to_string();
+    pub fn assemble_all(&mut self) -> Result<Vec<(String, String)>, String> {
+        let mut out = vec![(TEXT_SECTION.to_string(), vec![])];
         while let inst = self.assemble()? {
             match inst {
-                Inst { ty: EOINST } => return Ok(src),
+                Inst { ty: EOINST } => {
+                    return Ok(out
+                        .into_iter()
+                        .map(|(section, bytes)| (section, gen_words(&bytes)))
+                        .collect())
+                }
+                // .bss はアドレスだけを持ち、出力するデータはない
+                _ if self.section == BSS_SECTION => {}
                 i => {
-                    let b = gen_bin(&i);
-                    src = format!("{}{}\n", src, b);
+                    let b = gen_bytes(&i);
+                    match out.iter_mut().find(|(s, _)| *s == self.section) {
+                        Some((_, bytes)) => bytes.extend(b),
+                        None => out.push((self.section.clone(), b)),
+                    }
                 }
             }
         }
         Err("Assembler::assemble_all: unreachable !!".to_string())
     }
+}
 
-    //
-    fn make_symbol_table(&mut self) -> HashMap<String, usize> {
-        unimplemented!("Assemble::make_symbol_table: make_symbol_table have not implemented yet.")
+// label(rs1) のアドレスを作るために、上位を入れた rd に rs1 を足す命令を返す
+// rs1 が 0 なら何も足さない
+fn add_index(rd: isize, rs1: isize) -> Option<AsmKind> {
+    (rs1 != 0).then_some(ADD {
+        rs2: rs1,
+        rs1: rd,
+        rd,
+    })
+}
+
+// I 形式と S 形式の即値が 12bit の符号付き整数に、シフト量が 5bit に、U 形式の即値が 20bit に収まるかを確かめる
+// 命令形式に変換するときは下位の bit だけを使うので、範囲外の値はここでエラーにする
+fn check_imm(kind: &AsmKind) -> Result<(), String> {
+    match *kind {
+        LW { imm, .. }
+        | SW { imm, .. }
+        | ADDI { imm, .. }
+        | ANDI { imm, .. }
+        | ORI { imm, .. }
+        | XORI { imm, .. }
+        | SLTI { imm, .. }
+        | SLTIU { imm, .. }
+        | JALR { imm, .. }
+            if !(-2048..=2047).contains(&imm) =>
+        {
+            Err(format!(
+                "Assembler::check_imm: immediate {} is out of range (-2048..=2047)",
+                imm
+            ))
+        }
+        SLLI { imm, .. } | SRLI { imm, .. } | SRAI { imm, .. } if !(0..=31).contains(&imm) => {
+            Err(format!(
+                "Assembler::check_imm: shift amount {} is out of range (0..=31)",
+                imm
+            ))
+        }
+        // U 形式の即値は GNU as と同じく符号なしの 20bit
+        LUI { imm, .. } | AUIPC { imm, .. } if !(0..=0xfffff).contains(&imm) => Err(format!(
+            "Assembler::check_imm: immediate {:#x} is out of range (0..=0xfffff)",
+            imm
+        )),
+        _ => Ok(()),
     }
 }
 
@@ -290,13 +1027,21 @@ impl Assembler {
 //     Err("unreachable in assemble_hex !!".to_string())
 // }
 
+#[cfg(test)]
 mod assemble_tests {
-    use crate::asm::{Asm, AsmKind};
+    use std::collections::HashMap;
+
     use crate::assembler::Assembler;
-    use crate::inst::{Inst, InstType::*};
-    use crate::label_table::make_label_table;
+    use crate::code_gen::gen_bin;
+    use crate::inst::{Inst, InstType, InstType::*};
+    use crate::label_table::{
+        collect_labels, make_label_table, make_label_table_with_bases, parse_all,
+        place_data_sections,
+    };
     use crate::lexer::*;
     use crate::parser::*;
+    use crate::symbol_table::Binding;
+    use crate::token::Loc;
 
     #[test]
     fn test_assembler_i_lw() {
@@ -318,7 +1063,7 @@ mod assemble_tests {
 
     #[test]
     fn test_assembler_s_sw() {
-        let s: &str = "sw 6, 2357(0)\n";
+        let s: &str = "sw 6, -1739(0)\n";
         let mut l = Lexer::new(s);
         let mut p = Parser::new(&mut l);
         let (a, lt) = make_label_table(&mut p).unwrap();
@@ -414,7 +1159,7 @@ mod assemble_tests {
 
     #[test]
     fn test_assembler_r_or() {
-        let s: &str = "or 0, 100, 521\n";
+        let s: &str = "or 0, 10, 21\n";
         let mut l = Lexer::new(s);
         let mut p = Parser::new(&mut l);
         let (a, lt) = make_label_table(&mut p).unwrap();
@@ -422,8 +1167,8 @@ mod assemble_tests {
         let inst_ty = a.assemble().unwrap().ty;
         let expect = R {
             funct7: 0b0000000,
-            rs2: 521,
-            rs1: 100,
+            rs2: 21,
+            rs1: 10,
             funct3: 0b110,
             rd: 0,
             opcode: 0b0110011,
@@ -434,7 +1179,7 @@ mod assemble_tests {
 
     #[test]
     fn test_assembler_r_xor() {
-        let s: &str = "xor 24, 111, 666\n";
+        let s: &str = "xor 24, 11, 26\n";
         let mut l = Lexer::new(s);
         let mut p = Parser::new(&mut l);
         let (a, lt) = make_label_table(&mut p).unwrap();
@@ -442,8 +1187,8 @@ mod assemble_tests {
         let inst_ty = a.assemble().unwrap().ty;
         let expect = R {
             funct7: 0b0000000,
-            rs2: 666,
-            rs1: 111,
+            rs2: 26,
+            rs1: 11,
             funct3: 0b100,
             rd: 24,
             opcode: 0b0110011,
@@ -648,14 +1393,854 @@ mod assemble_tests {
         let (a, lt) = make_label_table(&mut p).unwrap();
         let mut a = Assembler::new(a, lt);
         let inst_ty = a.assemble().unwrap().ty;
-        let expect = I {
-            imm: 0b010000000000 + 11,
-            rs1: 6,
-            funct3: 0b101,
-            rd: 5,
-            opcode: 0b0010011,
+        let expect = B {
+            imm_1: 0,
+            rs2: 6,
+            rs1: 5,
+            funct3: 0b000,
+            imm_2: 0b01010,
+            opcode: 0b1100011,
+        };
+
+        assert_eq!(inst_ty, expect);
+    }
+
+    #[test]
+    fn test_assembler_b_bne_label() {
+        let s: &str = "loop:\naddi 1, 1, 1\nbne 1, 2, loop\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        a.assemble().unwrap();
+        let inst_ty = a.assemble().unwrap().ty;
+        // offset = -4
+        let expect = B {
+            imm_1: 0b1111111,
+            rs2: 2,
+            rs1: 1,
+            funct3: 0b001,
+            imm_2: 0b11101,
+            opcode: 0b1100011,
         };
 
         assert_eq!(inst_ty, expect);
     }
+
+    #[test]
+    fn test_assembler_b_undefined_label() {
+        let s: &str = "blt 1, 2, nowhere\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        assert!(a.assemble().is_err());
+    }
+
+    #[test]
+    fn test_assembler_hi_lo() {
+        let s: &str = "lui a0, %hi(msg)\naddi a0, a0, %lo(msg)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, mut lt) = make_label_table(&mut p).unwrap();
+        // 下位12bitが負になるアドレス
+        lt.define("msg", 0x12345fff, ".data", Loc::default())
+            .unwrap();
+        let mut a = Assembler::new(a, lt);
+        let lui = a.assemble().unwrap().ty;
+        let addi = a.assemble().unwrap().ty;
+        assert_eq!(
+            lui,
+            U {
+                imm: 0x12346,
+                rd: 10,
+                opcode: 0b0110111,
+            }
+        );
+        assert_eq!(
+            addi,
+            I {
+                imm: -1,
+                rs1: 10,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }
+        );
+    }
+
+    #[test]
+    fn test_assembler_reloc_addend() {
+        let s: &str = "lui a0, %hi(buf+4)\naddi a0, a0, %lo(buf+4)\nlw a1, %lo(buf-4)(a0)\n.L1:\nauipc a2, %pcrel_hi(msg+8)\naddi a2, a2, %pcrel_lo(.L1)\nmsg:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, mut lt) = make_label_table(&mut p).unwrap();
+        // 加数を足すと %hi の丸めが変わるアドレス
+        lt.define("buf", 0x123457fc, ".data", Loc::default())
+            .unwrap();
+        let mut a = Assembler::new(a, lt);
+        let imms = (0..5)
+            .map(|_| match a.assemble().unwrap().ty {
+                U { imm, .. } | I { imm, .. } => imm,
+                ty => panic!("unexpected {:?}", ty),
+            })
+            .collect::<Vec<_>>();
+        // msg(20) + 8 - .L1(12) = 16
+        assert_eq!(imms, vec![0x12346, -2048, 2040, 0, 16]);
+    }
+
+    #[test]
+    fn test_assembler_pcrel_hi_lo() {
+        let s: &str =
+            "addi 0, 0, 0\nL1:\nauipc a0, %pcrel_hi(msg)\naddi a0, a0, %pcrel_lo(L1)\nmsg:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        a.assemble().unwrap();
+        let auipc = a.assemble().unwrap().ty;
+        let addi = a.assemble().unwrap().ty;
+        // msg(12) - L1(4) = 8
+        assert_eq!(
+            auipc,
+            U {
+                imm: 0,
+                rd: 10,
+                opcode: 0b0010111,
+            }
+        );
+        assert_eq!(
+            addi,
+            I {
+                imm: 8,
+                rs1: 10,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }
+        );
+    }
+
+    #[test]
+    fn test_assembler_pcrel_lo_without_auipc() {
+        let s: &str = "L1:\naddi a0, a0, %pcrel_lo(L1)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        assert!(a.assemble().is_err());
+    }
+
+    #[test]
+    fn test_assembler_b_local_label() {
+        let s: &str = "1:\nbeq 1, 2, 1f\nbne 1, 2, 1b\n1:\nblt 1, 2, 1b\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        // offset = 8
+        let expect = B {
+            imm_1: 0,
+            rs2: 2,
+            rs1: 1,
+            funct3: 0b000,
+            imm_2: 0b01000,
+            opcode: 0b1100011,
+        };
+        assert_eq!(a.assemble().unwrap().ty, expect);
+        // offset = -4
+        let expect = B {
+            imm_1: 0b1111111,
+            rs2: 2,
+            rs1: 1,
+            funct3: 0b001,
+            imm_2: 0b11101,
+            opcode: 0b1100011,
+        };
+        assert_eq!(a.assemble().unwrap().ty, expect);
+        // offset = 0
+        let expect = B {
+            imm_1: 0,
+            rs2: 2,
+            rs1: 1,
+            funct3: 0b100,
+            imm_2: 0,
+            opcode: 0b1100011,
+        };
+        assert_eq!(a.assemble().unwrap().ty, expect);
+    }
+
+    #[test]
+    fn test_assembler_b_local_label_not_found() {
+        let s: &str = "beq 1, 2, 1b\n1:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        assert!(a.assemble().is_err());
+    }
+
+    #[test]
+    fn test_assembler_pcrel_local_label() {
+        let s: &str = "1:\nauipc a0, %pcrel_hi(2f)\naddi a0, a0, %pcrel_lo(1b)\n2:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        a.assemble().unwrap();
+        let addi = a.assemble().unwrap().ty;
+        assert_eq!(
+            addi,
+            I {
+                imm: 8,
+                rs1: 10,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }
+        );
+    }
+
+    // 全ての命令をアセンブルする
+    fn assemble_src(s: &str) -> Result<Vec<InstType>, String> {
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p)?;
+        let mut a = Assembler::new(a, lt);
+        let mut insts = vec![];
+        loop {
+            match a.assemble()?.ty {
+                EOINST => return Ok(insts),
+                ty => insts.push(ty),
+            }
+        }
+    }
+
+    // 命令のバイナリ文字列を返す
+    fn bin(ty: &InstType) -> String {
+        gen_bin(&Inst { ty: ty.clone() })
+    }
+
+    #[test]
+    fn test_assembler_i_logical_imm() {
+        let insts = assemble_src("andi 1, 2, 3\nori 1, 2, 3\nxori 1, 2, -1\n").unwrap();
+        let funct3s = insts
+            .iter()
+            .map(|ty| match ty {
+                I { funct3, .. } => *funct3,
+                _ => -1,
+            })
+            .collect::<Vec<isize>>();
+        assert_eq!(funct3s, vec![0b111, 0b110, 0b100]);
+        assert_eq!(bin(&insts[2]), "11111111111100010100000010010011");
+    }
+
+    #[test]
+    fn test_assembler_jal_jalr() {
+        let insts = assemble_src("loop:\njal loop\nj end\njalr x0, 4(ra)\nend:\n").unwrap();
+        // jal ra, -0 / jal x0, 8
+        assert_eq!(bin(&insts[0]), "00000000000000000000000011101111");
+        assert_eq!(bin(&insts[1]), "00000000100000000000000001101111");
+        assert_eq!(
+            insts[2],
+            I {
+                imm: 4,
+                rs1: 1,
+                funct3: 0b000,
+                rd: 0,
+                opcode: 0b1100111,
+            }
+        );
+        // jal x0, -4
+        let insts = assemble_src("loop:\nnop\nj loop\n").unwrap();
+        assert_eq!(bin(&insts[1]), "11111111110111111111000001101111");
+    }
+
+    #[test]
+    fn test_assembler_mem_operand() {
+        // lw rd, label と sw rs2, label, rt は auipc を使った PC 相対のアクセスになる
+        // label(rs1) は auipc で作ったアドレスに rs1 を足す
+        let s: &str = "lw a0, count\nsw a0, count, t0\nlw a1, table(a2)\nsw a1, table(a2), t1\ntable:\ncount:\n";
+        let insts = assemble_src(s).unwrap();
+        assert_eq!(insts.len(), 10);
+        // count(40) - 0
+        assert_eq!(
+            insts[1],
+            I {
+                imm: 0x28,
+                rs1: 10,
+                funct3: 0b010,
+                rd: 10,
+                opcode: 0b000011,
+            }
+        );
+        assert_eq!(
+            insts[2],
+            U {
+                imm: 0,
+                rd: 5,
+                opcode: 0b0010111,
+            }
+        );
+        // count(40) - 8
+        assert_eq!(
+            insts[3],
+            S {
+                imm_1: 0x1,
+                rs2: 10,
+                funct3: 0b010,
+                rs1: 5,
+                imm_2: 0,
+                opcode: 0b0100011,
+            }
+        );
+        // auipc a1, 0; add a1, a1, a2; lw a1, 24(a1)
+        assert_eq!(bin(&insts[4]), format!("{:032b}", 0x00000597));
+        assert_eq!(bin(&insts[5]), format!("{:032b}", 0x00c585b3));
+        assert_eq!(
+            insts[6],
+            I {
+                imm: 0x18,
+                rs1: 11,
+                funct3: 0b010,
+                rd: 11,
+                opcode: 0b000011,
+            }
+        );
+        // auipc t1, 0; add t1, t1, a2; sw a1, 12(t1)
+        assert_eq!(bin(&insts[7]), format!("{:032b}", 0x00000317));
+        assert_eq!(bin(&insts[8]), format!("{:032b}", 0x00c30333));
+        assert_eq!(bin(&insts[9]), format!("{:032b}", 0x00b32623));
+
+        // 12bit に収まらない距離も auipc で届く
+        let s = format!("lw a0, far(a1)\n{}far:\n", "nop\n".repeat(512));
+        let insts = assemble_src(&s).unwrap();
+        assert_eq!(bin(&insts[0]), format!("{:032b}", 0x00001517));
+        assert_eq!(bin(&insts[2]), format!("{:032b}", 0x80c52503u32));
+    }
+
+    #[test]
+    fn test_assembler_li() {
+        // 12bit に収まる値は addi だけ
+        let insts = assemble_src("li a0, -5\n").unwrap();
+        assert_eq!(
+            insts,
+            vec![I {
+                imm: -5,
+                rs1: 0,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }]
+        );
+        // 下位12bitが負になる値は上位を 1 繰り上げる
+        let insts = assemble_src("li a0, 0x12345fff\n").unwrap();
+        assert_eq!(
+            insts,
+            vec![
+                U {
+                    imm: 0x12346,
+                    rd: 10,
+                    opcode: 0b0110111,
+                },
+                I {
+                    imm: -1,
+                    rs1: 10,
+                    funct3: 0b000,
+                    rd: 10,
+                    opcode: 0b0010011,
+                },
+            ]
+        );
+        // 下位12bitが 0 の値は lui だけ
+        let insts = assemble_src("li a0, 0x12345000\n").unwrap();
+        assert_eq!(insts.len(), 1);
+        // 32bit の符号なし整数も受け付ける
+        let insts = assemble_src("li a0, 0xffffffff\n").unwrap();
+        assert_eq!(insts.len(), 1);
+    }
+
+    #[test]
+    fn test_assembler_call_la() {
+        let s: &str = "call f\nla a0, msg\ntail f\nli a0, 0x12345678\nf:\nret\nmsg:\n";
+        let insts = assemble_src(s).unwrap();
+        assert_eq!(insts.len(), 9);
+        // call f: f は 0x20 にある
+        assert_eq!(
+            insts[0],
+            U {
+                imm: 0,
+                rd: 1,
+                opcode: 0b0010111,
+            }
+        );
+        assert_eq!(
+            insts[1],
+            I {
+                imm: 0x20,
+                rs1: 1,
+                funct3: 0b000,
+                rd: 1,
+                opcode: 0b1100111,
+            }
+        );
+        // la a0, msg: auipc は 0x8、msg は 0x24 にある
+        assert_eq!(
+            insts[3],
+            I {
+                imm: 0x1c,
+                rs1: 10,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }
+        );
+        // tail f: auipc は 0x10
+        assert_eq!(
+            insts[5],
+            I {
+                imm: 0x10,
+                rs1: 6,
+                funct3: 0b000,
+                rd: 0,
+                opcode: 0b1100111,
+            }
+        );
+    }
+
+    #[test]
+    fn test_assembler_section() {
+        // buf は .data の先頭から 0x8 にあるので、lui と下位12bit の絶対アドレスで参照する
+        let s: &str = ".data\nnop\nnop\nbuf:\n.text\nla a0, buf\nlw a1, buf\nsw a1, buf, t0\n";
+        let insts = assemble_src(s).unwrap();
+        assert_eq!(insts.len(), 8);
+        assert_eq!(
+            insts[2],
+            U {
+                imm: 0,
+                rd: 10,
+                opcode: 0b0110111,
+            }
+        );
+        assert_eq!(
+            insts[3],
+            I {
+                imm: 8,
+                rs1: 10,
+                funct3: 0b000,
+                rd: 10,
+                opcode: 0b0010011,
+            }
+        );
+        assert_eq!(
+            insts[6],
+            U {
+                imm: 0,
+                rd: 5,
+                opcode: 0b0110111,
+            }
+        );
+
+        // 同じセクションのラベルは PC 相対のまま
+        let insts = assemble_src(".data\nla a0, buf\nbuf:\n").unwrap();
+        assert_eq!(
+            insts[0],
+            U {
+                imm: 0,
+                rd: 10,
+                opcode: 0b0010111,
+            }
+        );
+    }
+
+    #[test]
+    fn test_assembler_section_output() {
+        let s: &str = "nop\n.data\nnop\n.text\nnop\n.section .rodata.str\nnop\n.section .note\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let out = Assembler::new(a, lt).assemble_all().unwrap();
+        let nop = format!("{}\n", bin(&assemble_src("nop\n").unwrap()[0]));
+        assert_eq!(
+            out,
+            vec![
+                (".text".to_string(), nop.repeat(2)),
+                (".data".to_string(), nop.clone()),
+                (".rodata".to_string(), nop),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assembler_extern() {
+        let s: &str = ".extern putc, table\n.weak hook\n.globl main\nmain:\ncall putc\njal hook\nbeq a0, a1, putc\nla a0, table\nsw a0, table, t0\n.Lpc:\nauipc a1, %pcrel_hi(table+8)\nlw a1, %pcrel_lo(.Lpc)(a1)\n.data\n.word putc + 4, .Lpc\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let mut a = Assembler::new(a, lt);
+        let out = a.assemble_all().unwrap();
+
+        // 他のファイルで定義されるシンボルを参照する即値とデータは 0 にする
+        let word = |w: u32| format!("{:032b}\n", w);
+        let text = [
+            0x00000097, 0x000080e7, 0x000000ef, 0x00b50063, 0x00000537, 0x00050513, 0x000002b7,
+            0x00a2a023, 0x00000597, 0x0005a583,
+        ];
+        assert_eq!(out[0].1, text.iter().map(|w| word(*w)).collect::<String>());
+        assert_eq!(out[1].1, format!("{}{}", word(0), word(0x20)));
+
+        let relocs = a
+            .relocations()
+            .iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            relocs,
+            vec![
+                ".text    0x00000000 R_RISCV_CALL         putc",
+                ".text    0x00000008 R_RISCV_JAL          hook",
+                ".text    0x0000000c R_RISCV_BRANCH       putc",
+                ".text    0x00000010 R_RISCV_HI20         table",
+                ".text    0x00000014 R_RISCV_LO12_I       table",
+                ".text    0x00000018 R_RISCV_HI20         table",
+                ".text    0x0000001c R_RISCV_LO12_S       table",
+                ".text    0x00000020 R_RISCV_PCREL_HI20   table+8",
+                ".text    0x00000024 R_RISCV_PCREL_LO12_I .Lpc",
+                ".data    0x00000000 R_RISCV_32           putc+4",
+            ]
+        );
+        assert_eq!(a.symbols().get("main").unwrap().binding, Binding::Global);
+
+        // 宣言していない未定義のシンボルと、再配置できない大きさのデータはエラー
+        for (s, e) in [
+            ("call putc\n", "1:1: Assembler::lookup_label"),
+            (
+                ".extern putc\n.data\n.half putc\n",
+                "3:1: Assembler::external_refs",
+            ),
+        ]
+        .iter()
+        {
+            let err = assemble_src(s).unwrap_err();
+            assert!(err.starts_with(e), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_assembler_imm_range() {
+        for s in [
+            "addi a0, a0, 2047\n",
+            "addi a0, a0, -2048\n",
+            "lw a0, -2048(a1)\n",
+            "sw a0, 2047(a1)\n",
+            "slli a0, a0, 31\n",
+            "lui a0, 0xfffff\n",
+            "auipc a0, 0\n",
+        ]
+        .iter()
+        {
+            assert!(assemble_src(s).is_ok(), "{}", s);
+        }
+
+        // 範囲外の即値は下位の bit に切り詰めずにエラーにする
+        for s in [
+            "addi a0, a0, 5000\n",
+            "addi a0, a0, -2049\n",
+            "lw a0, 3000(a1)\n",
+            "sw a0, 2048(a1)\n",
+            "xori a0, a0, 4095\n",
+            "jalr ra, 2048(a0)\n",
+            "nop\n.equ OFF, 0x800\nlw a0, OFF(a1)\n",
+            "slli a0, a0, 32\n",
+            "srai a0, a0, -1\n",
+            "lui a0, 0x100000\n",
+            "auipc a0, -1\n",
+        ]
+        .iter()
+        {
+            let e = assemble_src(s).unwrap_err();
+            let line = s.lines().count();
+            assert!(
+                e.starts_with(&format!("{}:1: Assembler::check_imm", line)),
+                "{}: {}",
+                s,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_assembler_section_errors() {
+        let cases = [
+            // PC 相対の参照は、配置していない別のセクションのラベルに使えない
+            (".data\nbuf:\n.text\nj buf\n", "4:1: "),
+            (".data\nbuf:\n.text\ncall buf\n", "4:1: "),
+            ("f:\n.data\nbeqz a0, f\n", "3:1: "),
+            (".data\nbuf:\n.text\nauipc a0, %pcrel_hi(buf)\n", "4:1: "),
+            // .bss には命令を置けない
+            (".bss\nnop\n", "2:1: "),
+        ];
+        for (s, loc) in cases.iter() {
+            let e = assemble_src(s).unwrap_err();
+            assert!(e.starts_with(loc), "{}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_assembler_cross_section_pcrel() {
+        let s: &str = ".section .rodata
+.LC0:
+.word 1
+.text
+nop
+.L1:
+auipc a0, %pcrel_hi(.LC0)
+addi a0, a0, %pcrel_lo(.L1)
+call f
+j .LC0
+.data
+f:
+";
+        let mut bases = HashMap::new();
+        bases.insert(".text".to_string(), 0x8000);
+        bases.insert(".rodata".to_string(), 0x9000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        // .data は配置していないので、.data への PC 相対の参照はエラー
+        let e = Assembler::with_bases(a, lt, bases.clone())
+            .assemble_all()
+            .unwrap_err();
+        assert!(e.starts_with("9:1: "), "{}", e);
+        assert!(e.contains("not placed"), "{}", e);
+
+        bases.insert(".data".to_string(), 0x10000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        let out = Assembler::with_bases(a, lt, bases).assemble_all().unwrap();
+        let word = |w: u32| format!("{:032b}\n", w);
+        let expect = [
+            word(0x13),
+            // .LC0(0x9000) - .L1(0x8004) = 0xffc
+            word(0x00001517),
+            word(0xffc50513),
+            // f(0x10000) - 0x800c = 0x7ff4
+            word(0x00008097),
+            word(0xff4080e7),
+            // .LC0(0x9000) - 0x8014 = 0xfec
+            word(0x7ed0006f),
+        ]
+        .concat();
+        assert_eq!(out[0].1, expect);
+
+        // la, lw rd, label は配置した別のセクションにも auipc で届き、配置していなければ lui にする
+        let s: &str = ".data\nbuf:\n.text\nla a0, buf\n";
+        let mut bases = HashMap::new();
+        bases.insert(".data".to_string(), 0x1000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        let out = Assembler::with_bases(a, lt, bases.clone())
+            .assemble_all()
+            .unwrap();
+        assert_eq!(out[0].1, [word(0x00001537), word(0x00050513)].concat());
+        bases.insert(".text".to_string(), 0);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        let out = Assembler::with_bases(a, lt, bases).assemble_all().unwrap();
+        assert_eq!(out[0].1, [word(0x00001517), word(0x00050513)].concat());
+
+        // データのアドレス空間に詰めて置いたセクションは、--base で置いた .text から lui で参照する
+        let s: &str = ".data\n.word 1\n.rodata\nmsg:\n.text\nla a0, msg\n";
+        let mut placed = HashMap::new();
+        placed.insert(".text".to_string(), 0x8000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let a = parse_all(&mut p).unwrap();
+        let bases = place_data_sections(&a, &placed).unwrap();
+        let lt = collect_labels(&a, &bases).unwrap();
+        let out = Assembler::with_layout(a, lt, bases, placed.into_keys().collect())
+            .assemble_all()
+            .unwrap();
+        // msg は .data の後ろの 4 番地
+        assert_eq!(out[0].1, [word(0x00000537), word(0x00450513)].concat());
+    }
+
+    #[test]
+    fn test_assembler_data() {
+        let insts =
+            assemble_src(".byte 1, -1\n.half -2\n.word table\n.dword -1\ntable:\n").unwrap();
+        assert_eq!(
+            insts,
+            vec![
+                InstType::DATA {
+                    bytes: vec![0x01, 0xff]
+                },
+                InstType::DATA {
+                    bytes: vec![0xfe, 0xff]
+                },
+                InstType::DATA {
+                    bytes: vec![0x10, 0, 0, 0]
+                },
+                InstType::DATA {
+                    bytes: vec![0xff; 8]
+                },
+            ]
+        );
+
+        // 定数を先に書いた式と、同じセクションのラベルの差
+        let insts = assemble_src("nop\na:\nnop\nb:\n.word 4 + a, b - a, a - b\n").unwrap();
+        assert_eq!(
+            insts[2],
+            InstType::DATA {
+                bytes: vec![8, 0, 0, 0, 4, 0, 0, 0, 0xfc, 0xff, 0xff, 0xff]
+            }
+        );
+
+        // 符号付き、符号なしのどちらかで収まる値だけを受け付ける
+        assert!(assemble_src(".byte -128, 255\n.half -32768, 65535\n").is_ok());
+        let cases = [
+            (".byte 256\n", "1:1: "),
+            (".byte -129\n", "1:1: "),
+            ("nop\n.half 0x10000\n", "2:1: "),
+            (".word 0x100000000\n", "1:1: "),
+            (".word nowhere\n", "1:1: "),
+            // ラベルの差は同じセクションのラベルどうしでしか計算できない
+            ("a:\n.data\nb:\n.word b - a\n", "4:1: "),
+            (".globl x\na:\n.word x - a\n", "3:1: "),
+        ];
+        for (s, loc) in cases.iter() {
+            let e = assemble_src(s).unwrap_err();
+            assert!(e.starts_with(loc), "{}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_assembler_data_output() {
+        // データはリトルエンディアンで詰めて、32bit ごとに一行にする
+        let s: &str = ".data\n.byte 1, 2, 3\n.half 0x0504\n.word 0x0a090807\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let out = Assembler::new(a, lt).assemble_all().unwrap();
+        assert_eq!(
+            out[1],
+            (
+                ".data".to_string(),
+                format!(
+                    "{:032b}\n{:032b}\n{:032b}\n",
+                    0x04030201, 0x09080705, 0x0000000a
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_assembler_align() {
+        // .text では nop、それ以外のセクションでは 0 または fill で詰める
+        let s: &str = "nop\n.p2align 4\n.data\n.byte 1\n.balign 4\n.byte 2\n.balign 8, 0xee\n.text\n.balign 8\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let out = Assembler::new(a, lt).assemble_all().unwrap();
+        let word = |w: u32| format!("{:032b}\n", w);
+        assert_eq!(out[0].1, word(0x13).repeat(4));
+        assert_eq!(out[1].1, format!("{}{}", word(0x01), word(0xeeeeee02)));
+
+        // 命令の境界に揃っていない位置の .text の .align は 0 で埋めてから nop を並べる
+        let insts = assemble_src(".byte 1\n.balign 8\n").unwrap();
+        assert_eq!(
+            insts[1],
+            InstType::DATA {
+                bytes: vec![0, 0, 0, 0x13, 0, 0, 0]
+            }
+        );
+    }
+
+    #[test]
+    fn test_assembler_space() {
+        let s: &str = ".data\n.space 3, 0xaa\n.zero 1\n.fill 2, 4, 0xdeadbeef\n.fill 1, 2, -2\n.bss\n.space 0x1000\nend:\n.text\nla a0, end\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table(&mut p).unwrap();
+        let out = Assembler::new(a, lt).assemble_all().unwrap();
+        let word = |w: u32| format!("{:032b}\n", w);
+        // .bss は出力しないが、end のアドレスは確保した領域の後になる
+        assert_eq!(out.len(), 2);
+        // la a0, end: lui a0, 1; addi a0, a0, 0
+        assert_eq!(
+            out[0].1,
+            format!("{}{}", word(0x00001537), word(0x00050513))
+        );
+        assert_eq!(
+            out[1],
+            (
+                ".data".to_string(),
+                format!(
+                    "{}{}{}{}",
+                    word(0x00aaaaaa),
+                    word(0xdeadbeef),
+                    word(0xdeadbeef),
+                    word(0x0000fffe)
+                )
+            )
+        );
+
+        // .bss には 0 以外の値で埋める領域は置けない
+        for s in [
+            ".bss\n.space 4, 1\n",
+            ".bss\n.fill 1, 4, 1\n",
+            ".bss\n.balign 8, 1\n",
+        ]
+        .iter()
+        {
+            let e = assemble_src(s).unwrap_err();
+            assert!(e.starts_with("2:1: "), "{}: {}", s, e);
+        }
+        assert!(assemble_src(".bss\n.zero 4\n.fill 2, 4\n").is_ok());
+    }
+
+    #[test]
+    fn test_assembler_base_org() {
+        let s: &str = "j start\n.org 0x10\ntrap:\nlui a0, %hi(trap)\naddi a0, a0, %lo(trap)\nstart:\nj trap\n.word trap\n";
+        let mut bases = HashMap::new();
+        bases.insert(".text".to_string(), 0x8000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (a, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        let out = Assembler::with_bases(a, lt, bases).assemble_all().unwrap();
+        let word = |w: u32| format!("{:032b}\n", w);
+        let expect = [
+            // 分岐は PC 相対のまま: j start (+0x18)
+            word(0x0180006f),
+            // .org までは nop で詰める
+            word(0x13).repeat(3),
+            // 絶対アドレスは先頭アドレスを足した値: lui a0, 0x8; addi a0, a0, 0x10
+            word(0x00008537),
+            word(0x01050513),
+            // j trap (-0x8)
+            word(0xff9ff06f),
+            word(0x00008010),
+        ]
+        .concat();
+        assert_eq!(out[0].1, expect);
+    }
+
+    #[test]
+    fn test_assembler_misaligned_inst() {
+        let e = assemble_src("nop\n.byte 1\nnop\n").unwrap_err();
+        assert!(e.starts_with("3:1: "), "{}", e);
+        assert!(e.contains("0x5"), "{}", e);
+        assert!(assemble_src(".byte 1\n.balign 4\nnop\n").is_ok());
+        // .bss には .align だけを置ける
+        assert!(assemble_src(".bss\n.balign 8\n").is_ok());
+    }
+
+    #[test]
+    fn test_assembler_pseudo_error_location() {
+        let e = assemble_src("nop\ncall nowhere\n").unwrap_err();
+        assert!(e.starts_with("2:1: "), "{}", e);
+    }
 }
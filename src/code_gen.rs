@@ -2,9 +2,11 @@ use crate::inst::InstType::*;
 use crate::inst::*;
 
 // 命令形式に従って、バイナリを生成する
+// データはリトルエンディアンの値として表示する
 pub fn gen_bin(inst: &Inst) -> String {
     match inst.ty {
         EOINST => "".to_string(),
+        DATA { ref bytes } => bytes.iter().rev().map(|b| format!("{:08b}", b)).collect(),
         I {
             imm,
             rs1,
@@ -13,8 +15,15 @@ pub fn gen_bin(inst: &Inst) -> String {
             opcode,
         } => format!(
             "{:012b}{:05b}{:03b}{:05b}{:07b}",
-            imm, rs1, funct3, rd, opcode
+            imm & 0xfff,
+            rs1,
+            funct3,
+            rd,
+            opcode
         ),
+        U { imm, rd, opcode } | J { imm, rd, opcode } => {
+            format!("{:020b}{:05b}{:07b}", imm & 0xfffff, rd, opcode)
+        }
         S {
             imm_1,
             rs2,
@@ -51,9 +60,37 @@ pub fn gen_bin(inst: &Inst) -> String {
     }
 }
 
+// 命令またはデータをメモリに置く順のバイト列に変換する
+// 命令はリトルエンディアンの 32bit の値として置く
+pub fn gen_bytes(inst: &Inst) -> Vec<u8> {
+    match &inst.ty {
+        EOINST => vec![],
+        DATA { bytes } => bytes.clone(),
+        _ => {
+            let word = gen_bin(inst)
+                .bytes()
+                .fold(0u32, |v, c| (v << 1) | (c - b'0') as u32);
+            word.to_le_bytes().to_vec()
+        }
+    }
+}
+
+// メモリの内容を 32bit ごとに一行のバイナリに変換する
+// 最後の半端なバイトは 0 で埋める
+pub fn gen_words(bytes: &[u8]) -> String {
+    bytes
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            format!("{:032b}\n", u32::from_le_bytes(word))
+        })
+        .collect()
+}
+
 // バイナリをhexに変換する
 pub fn gen_hex(inst: &Inst) -> String {
-    let s = gen_bin(&inst);
+    let s = gen_bin(inst);
     let mut cs = [0, 0, 0, 0, 0, 0, 0, 0];
     cs[7] = u8::from_str_radix(&s[0..4], 2).unwrap();
     cs[6] = u8::from_str_radix(&s[4..8], 2).unwrap();
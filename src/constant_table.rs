@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+// .equ, .set, = で定義される定数
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Constant {
+    value: isize,
+    // .set と = で定義された定数は再定義できる
+    redefinable: bool,
+}
+
+// アセンブル時に値が決まる定数の表
+// pc から決まるラベル (label_table) とは別に管理する
+#[derive(Debug, Clone, Default)]
+pub struct ConstantTable {
+    constants: HashMap<String, Constant>,
+}
+
+impl ConstantTable {
+    // ConstantTableのコンストラクター
+    pub fn new() -> Self {
+        Self {
+            constants: HashMap::new(),
+        }
+    }
+
+    // 定数を定義する
+    // .equ で定義された定数と、.set で定義された定数を .equ で再定義することはできない
+    pub fn define(&mut self, name: &str, value: isize, redefinable: bool) -> Result<(), String> {
+        if let Some(c) = self.constants.get(name) {
+            if !c.redefinable || !redefinable {
+                return Err(format!(
+                    "ConstantTable::define: {:?} is already defined",
+                    name
+                ));
+            }
+        }
+        self.constants
+            .insert(name.to_string(), Constant { value, redefinable });
+        Ok(())
+    }
+
+    // 定数の値を返す
+    pub fn get(&self, name: &str) -> Option<isize> {
+        self.constants.get(name).map(|c| c.value)
+    }
+}
+
+#[cfg(test)]
+mod constant_table_tests {
+    use crate::constant_table::*;
+
+    #[test]
+    fn test_define_equ() {
+        let mut ct = ConstantTable::new();
+        ct.define("UART_BASE", 0x10000000, false).unwrap();
+        assert_eq!(ct.get("UART_BASE"), Some(0x10000000));
+        assert_eq!(ct.get("LED"), None);
+        assert!(ct.define("UART_BASE", 0, false).is_err());
+        assert!(ct.define("UART_BASE", 0, true).is_err());
+    }
+
+    #[test]
+    fn test_define_set() {
+        let mut ct = ConstantTable::new();
+        ct.define("COUNT", 1, true).unwrap();
+        ct.define("COUNT", 2, true).unwrap();
+        assert_eq!(ct.get("COUNT"), Some(2));
+        assert!(ct.define("COUNT", 3, false).is_err());
+    }
+}
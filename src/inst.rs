@@ -1,6 +1,6 @@
 use std::fmt::{Display, Formatter, Result};
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum InstType {
     EOINST,
     // I形式の命令
@@ -36,6 +36,14 @@ pub enum InstType {
         rd: isize,
         opcode: isize,
     },
+    // U形式の命令
+    // | 31 ------------------------- 12 | 11 --- 7 | 6 --- 0 |
+    //            imm[31:12]                  rd       opcode
+    U {
+        imm: isize,
+        rd: isize,
+        opcode: isize,
+    },
     // B形式の命令
     // | 31 ----- 25 | 24 --- 20 | 19 --- 15 | 14 --- 12 | 11 --- 7 | 6 --- 0 |
     //       imm          rs2         rs1       funct3        rd       opcode
@@ -47,6 +55,19 @@ pub enum InstType {
         imm_2: isize,
         opcode: isize,
     },
+    // J形式の命令
+    // | 31 ------------------------- 12 | 11 --- 7 | 6 --- 0 |
+    //     imm[20|10:1|11|19:12]              rd       opcode
+    J {
+        imm: isize,
+        rd: isize,
+        opcode: isize,
+    },
+    // 命令ではないデータ (.byte, .word など)
+    // メモリに置く順のバイト列
+    DATA {
+        bytes: Vec<u8>,
+    },
 }
 
 impl Display for InstType {
@@ -84,6 +105,13 @@ impl Display for InstType {
             } => {
                 write!(f, "R {{ funct7: {:#09b}, rs2: {:#07b}, rs1: {:#07b}, funct3: {:#05b}, rd: {:#07b}, opcode: {:#09b} }}", funct7, rs2, rs1, funct3, rd, opcode)
             }
+            Self::U { imm, rd, opcode } => {
+                write!(
+                    f,
+                    "U {{ imm: {:#022b}, rd: {:#07b}, opcode: {:#09b} }}",
+                    imm, rd, opcode
+                )
+            }
             Self::B {
                 imm_1,
                 rs2,
@@ -92,7 +120,17 @@ impl Display for InstType {
                 imm_2,
                 opcode,
             } => {
-                write!(f, "S {{ imm_1: {:#09b}, rs2: {:#07b}, rs1: {:#07b}, funct3: {:#05b}, imm_2: {:#07b}, opcode: {:#09b} }}", imm_1, rs2, rs1, funct3, imm_2, opcode)
+                write!(f, "B {{ imm_1: {:#09b}, rs2: {:#07b}, rs1: {:#07b}, funct3: {:#05b}, imm_2: {:#07b}, opcode: {:#09b} }}", imm_1, rs2, rs1, funct3, imm_2, opcode)
+            }
+            Self::J { imm, rd, opcode } => {
+                write!(
+                    f,
+                    "J {{ imm: {:#022b}, rd: {:#07b}, opcode: {:#09b} }}",
+                    imm, rd, opcode
+                )
+            }
+            Self::DATA { bytes } => {
+                write!(f, "DATA {{ bytes: {:02x?} }}", bytes)
             }
         }
     }
@@ -1,11 +1,26 @@
 use std::collections::HashMap;
 
-use crate::asm::{Asm, AsmKind::*};
+use crate::asm::{Asm, AsmKind::*, SymbolSize, ADDRESS_SPACE};
 use crate::parser::Parser;
+use crate::symbol_table::SymbolTable;
 
-pub fn make_label_table<'a>(
-    p: &'a mut Parser<'a>,
-) -> Result<(Vec<Asm>, HashMap<String, isize>), String> {
+pub fn make_label_table(p: &mut Parser) -> Result<(Vec<Asm>, SymbolTable), String> {
+    make_label_table_with_bases(p, &HashMap::new())
+}
+
+// セクションの先頭アドレスを指定してラベル表を作る
+// bases にないセクションは 0 から始まる
+pub fn make_label_table_with_bases(
+    p: &mut Parser,
+    bases: &HashMap<String, isize>,
+) -> Result<(Vec<Asm>, SymbolTable), String> {
+    let vs = parse_all(p)?;
+    let label_table = collect_labels(&vs, bases)?;
+    Ok((vs, label_table))
+}
+
+// 命令列の末尾 (EOASM) まで parse する
+pub fn parse_all(p: &mut Parser) -> Result<Vec<Asm>, String> {
     let mut vs = vec![];
 
     loop {
@@ -17,15 +32,505 @@ pub fn make_label_table<'a>(
         vs.push(a);
     }
 
-    let mut pc = 0;
-    let mut label_table = HashMap::new();
+    Ok(vs)
+}
+
+// parse 済みの命令列からラベル表を作る
+// ラベルの値は、そのラベルがあるセクションの先頭アドレスに先頭からの位置を足した値
+// .globl などの binding と .size の大きさもラベル表に記録する
+pub fn collect_labels(vs: &[Asm], bases: &HashMap<String, isize>) -> Result<SymbolTable, String> {
+    let mut label_table = SymbolTable::new();
+    // 数字ラベルごとの定義回数
+    let mut local_labels = HashMap::new();
+    let locs = locate(vs, bases)?;
+
+    for (a, (section, pc)) in vs.iter().zip(locs.iter()) {
+        match &a.kind {
+            // .org で後ろには戻れない
+            // .org の値はセクションの先頭からの位置なので、先頭からの位置で比べる
+            ORG { addr, .. } if *addr < pc - section_base(bases, section) => {
+                return Err(format!(
+                    "{}: make_label_table: .org {:#x} is before the current offset {:#x} in {}",
+                    a.loc,
+                    addr,
+                    pc - section_base(bases, section),
+                    section
+                ));
+            }
+            // 数字ラベルは再定義できるので、定義回数をつけて区別する
+            LABEL { l } if is_local_label(l) => {
+                let count = local_labels.entry(l.clone()).or_insert(0);
+                label_table.define(&local_label_key(l, *count), *pc, section, a.loc.clone())?;
+                *count += 1;
+            }
+            LABEL { l } => label_table
+                .define(l, *pc, section, a.loc.clone())
+                .map_err(|e| format!("{}: {}", a.loc, e))?,
+            BIND { binding, names } => {
+                for name in names.iter() {
+                    label_table.declare(name, *binding, a.loc.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // .size はすべてのラベルを定義してから計算する
+    for (a, (section, pc)) in vs.iter().zip(locs.iter()) {
+        if let SIZE { name, size } = &a.kind {
+            symbol_size(&label_table, size, section, *pc)
+                .and_then(|size| label_table.set_size(name, size))
+                .map_err(|e| format!("{}: {}", a.loc, e))?;
+        }
+    }
+
+    Ok(label_table)
+}
+
+// .size の大きさを計算する
+// アドレスの差は同じセクションのラベルどうしでしか計算できない
+fn symbol_size(
+    label_table: &SymbolTable,
+    size: &SymbolSize,
+    section: &str,
+    pc: isize,
+) -> Result<isize, String> {
+    let size = match size {
+        SymbolSize::Const(size) => *size,
+        SymbolSize::Diff { end, start } => label_diff(label_table, end, start, section, pc)?,
+    };
+    if size < 0 {
+        return Err(format!("make_label_table: size {} is negative", size));
+    }
+    Ok(size)
+}
+
+// end - start のアドレスの差を計算する
+// end が None なら現在のアドレスを使う
+fn label_diff(
+    label_table: &SymbolTable,
+    end: &Option<String>,
+    start: &str,
+    section: &str,
+    pc: isize,
+) -> Result<isize, String> {
+    let lookup = |label: &str| match label_table.get(label) {
+        Some(sym) if sym.is_defined() => Ok((sym.value, sym.section.clone().unwrap_or_default())),
+        _ => Err(format!(
+            "make_label_table: label {:?} is not defined",
+            label
+        )),
+    };
+    let (start_addr, start_section) = lookup(start)?;
+    let (end_addr, end_section) = match end {
+        Some(end) => lookup(end)?,
+        None => (pc, section.to_string()),
+    };
+    if start_section != end_section {
+        return Err(format!(
+            "make_label_table: size cannot be calculated across sections {} and {}",
+            start_section, end_section
+        ));
+    }
+    Ok(end_addr - start_addr)
+}
+
+// 各 Asm を置くセクションとアドレスを返す
+// セクションごとに位置カウンタを持ち、切り替えても続きから数える
+// 32bit のアドレス空間からはみ出す Asm があればエラーにする
+pub fn locate(vs: &[Asm], bases: &HashMap<String, isize>) -> Result<Vec<(String, isize)>, String> {
+    let mut section = TEXT_SECTION.to_string();
+    let mut pcs: HashMap<String, isize> = HashMap::new();
+    let mut pc = section_base(bases, &section);
+    let mut locs = Vec::with_capacity(vs.len());
 
     for a in vs.iter() {
-        if let LABEL { l } = a.kind.clone() {
-            label_table.insert(l, pc);
+        if let SECTION { name } = &a.kind {
+            pcs.insert(std::mem::replace(&mut section, name.clone()), pc);
+            pc = *pcs.get(name).unwrap_or(&section_base(bases, name));
         }
-        pc += 4;
+        locs.push((section.clone(), pc));
+        pc = match pc.checked_add(a.size_at(pc, section_base(bases, &section))) {
+            Some(end) if end <= ADDRESS_SPACE => end,
+            _ => {
+                return Err(format!(
+                    "{}: locate: {} does not fit in the 32-bit address space",
+                    a.loc, section
+                ))
+            }
+        };
     }
+    Ok(locs)
+}
 
-    Ok((vs, label_table))
+// セクションごとに (セクション名, 先頭アドレス, 末尾のアドレス) を返す
+// .text を先頭にして、それ以外のセクションは現れた順に並べる
+pub fn section_extents(
+    vs: &[Asm],
+    bases: &HashMap<String, isize>,
+) -> Result<Vec<(String, isize, isize)>, String> {
+    let base = section_base(bases, TEXT_SECTION);
+    let mut extents = vec![(TEXT_SECTION.to_string(), base, base)];
+    for (a, (section, pc)) in vs.iter().zip(locate(vs, bases)?) {
+        let end = pc + a.size_at(pc, section_base(bases, &section));
+        match extents.iter_mut().find(|(s, _, _)| *s == section) {
+            Some((_, _, e)) => *e = (*e).max(end),
+            None => {
+                let start = section_base(bases, &section);
+                extents.push((section, start, end.max(start)));
+            }
+        }
+    }
+    Ok(extents)
+}
+
+// セクションの先頭を揃える境界を返す
+// セクション内の .align の最大値で、最低 4 バイトに揃える
+// locs は locate で求めた各 Asm の (セクション, アドレス)
+pub fn section_align(vs: &[Asm], locs: &[(String, isize)], section: &str) -> isize {
+    vs.iter()
+        .zip(locs.iter())
+        .filter(|(_, (s, _))| s == section)
+        .filter_map(|(a, _)| match a.kind {
+            ALIGN { align, .. } => Some(align),
+            _ => None,
+        })
+        .fold(4, isize::max)
+}
+
+// bases で配置していない .text 以外のセクションを、データのアドレス空間の 0 番地から
+// 現れた順に詰めて置き、bases に加えた先頭アドレスの表を返す
+// .data, .rodata, .bss がどれも 0 番地から始まって、ラベルのアドレスが重ならないようにする
+pub fn place_data_sections(
+    vs: &[Asm],
+    bases: &HashMap<String, isize>,
+) -> Result<HashMap<String, isize>, String> {
+    let locs = locate(vs, &HashMap::new())?;
+    let mut placed = bases.clone();
+    let mut cursor = 0;
+    for (section, _, _) in section_extents(vs, &HashMap::new())? {
+        if section == TEXT_SECTION || bases.contains_key(&section) {
+            continue;
+        }
+        let align = section_align(vs, &locs, &section);
+        let base = (cursor + align - 1) / align * align;
+        placed.insert(section.clone(), base);
+
+        // .align があるとセクションの大きさは先頭アドレスで変わるので、置いてから計算する
+        cursor = section_extents(vs, &placed)?
+            .into_iter()
+            .find(|(s, _, _)| *s == section)
+            .map_or(base, |(_, _, end)| end);
+    }
+    Ok(placed)
+}
+
+// 最初の命令を置くセクション
+pub const TEXT_SECTION: &str = ".text";
+// 初期値を持たず、出力しないセクション
+pub const BSS_SECTION: &str = ".bss";
+
+// セクションの先頭アドレスを返す
+pub fn section_base(bases: &HashMap<String, isize>, section: &str) -> isize {
+    *bases.get(section).unwrap_or(&0)
+}
+
+// 1: のような数字ラベルかを判定する
+pub fn is_local_label(l: &str) -> bool {
+    !l.is_empty() && l.bytes().all(|c| c.is_ascii_digit())
+}
+
+// n 番目に定義された数字ラベルのラベル表でのキーを返す
+pub fn local_label_key(l: &str, n: usize) -> String {
+    format!("{}:{}", l, n)
+}
+
+// 1b, 1f のような数字ラベルの参照を、ラベル表でのキーに変換する
+// local_labels はその位置までに定義された数字ラベルの定義回数
+// 数字ラベルの参照でなければ None を返す
+pub fn resolve_local_label(
+    label: &str,
+    local_labels: &HashMap<String, usize>,
+) -> Option<Result<String, String>> {
+    let (l, dir) = label.split_at(label.len().checked_sub(1)?);
+    if !is_local_label(l) || (dir != "b" && dir != "f") {
+        return None;
+    }
+
+    let count = *local_labels.get(l).unwrap_or(&0);
+    let key = match dir {
+        // 直前に定義されたラベル
+        "b" if count > 0 => Ok(local_label_key(l, count - 1)),
+        "b" => Err(format!(
+            "resolve_local_label: no previous definition of label {:?}",
+            l
+        )),
+        // 直後に定義されるラベル
+        _ => Ok(local_label_key(l, count)),
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod label_table_tests {
+    use std::collections::HashMap;
+
+    use crate::label_table::*;
+    use crate::lexer::Lexer;
+    use crate::symbol_table::Binding;
+
+    #[test]
+    fn test_label_table() {
+        let s: &str = "addi 1, 1, 1\nloop:\nadd 1, 2, 3\nend:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("loop"), Some(4));
+        assert_eq!(lt.value("end"), Some(8));
+    }
+
+    #[test]
+    fn test_label_table_duplicate_label() {
+        let s: &str = "loop:\nadd 1, 2, 3\nloop:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        assert!(make_label_table(&mut p).is_err());
+    }
+
+    #[test]
+    fn test_label_table_case_sensitive() {
+        let s: &str = "Loop:\nadd a0, a0, a0\nloop:\nLOOP:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("Loop"), Some(0));
+        assert_eq!(lt.value("loop"), Some(4));
+        assert_eq!(lt.value("LOOP"), Some(4));
+    }
+
+    #[test]
+    fn test_label_table_local_label() {
+        let s: &str = "1:\nadd 1, 2, 3\n1:\nadd 1, 2, 3\n1:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value(&local_label_key("1", 0)), Some(0));
+        assert_eq!(lt.value(&local_label_key("1", 1)), Some(4));
+        assert_eq!(lt.value(&local_label_key("1", 2)), Some(8));
+    }
+
+    #[test]
+    fn test_label_table_pseudo_size() {
+        // 疑似命令は展開後の命令数だけアドレスを進める
+        let s: &str = "li a0, 1\na:\nli a0, 0x12345\nb:\nli a0, 0x12000\nc:\ncall c\nd:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("a"), Some(4));
+        assert_eq!(lt.value("b"), Some(12));
+        assert_eq!(lt.value("c"), Some(16));
+        assert_eq!(lt.value("d"), Some(24));
+    }
+
+    #[test]
+    fn test_label_table_data_size() {
+        // データは実際のバイト数だけアドレスを進める
+        let s: &str =
+            ".data\na:\n.asciz \"abc\"\nb:\n.byte 1, 2\nc:\n.ascii \"あ\"\nd:\n.dword 0\ne:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("a"), Some(0));
+        assert_eq!(lt.value("b"), Some(4));
+        assert_eq!(lt.value("c"), Some(6));
+        assert_eq!(lt.value("d"), Some(9));
+        assert_eq!(lt.value("e"), Some(17));
+    }
+
+    #[test]
+    fn test_label_table_align() {
+        // .align の後のラベルは揃えたアドレスになる
+        let s: &str = ".data\n.byte 1\n.balign 4\na:\n.byte 1\n.p2align 3,,2\nb:\n.p2align 3,,8\nc:\n.balign 2\nd:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("a"), Some(4));
+        // 詰めるバイト数が max を超える場合は揃えない
+        assert_eq!(lt.value("b"), Some(5));
+        assert_eq!(lt.value("c"), Some(8));
+        assert_eq!(lt.value("d"), Some(8));
+    }
+
+    #[test]
+    fn test_label_table_space() {
+        // .bss の領域も位置カウンタを進める
+        let s: &str = ".bss\nstack:\n.space 256\nstack_top:\nbuf:\n.fill 4, 8\nend:\n.data\nv:\n.zero 3\nw:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("stack"), Some(0));
+        assert_eq!(lt.value("stack_top"), Some(256));
+        assert_eq!(lt.value("end"), Some(288));
+        assert_eq!(lt.value("w"), Some(3));
+
+        // 32bit のアドレス空間の最後までは置けるが、はみ出す位置にはエラーを出す
+        let s: &str = ".bss\n.space 0xffffffff\nend:\n.space 1\n";
+        let mut p = Parser::new(Lexer::new(s));
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("end"), Some(0xffffffff));
+        let s: &str = ".bss\n.space 0x100000000\nnop\n";
+        let mut p = Parser::new(Lexer::new(s));
+        let e = make_label_table(&mut p).unwrap_err();
+        assert!(e.starts_with("3:1: locate: "), "{}", e);
+    }
+
+    #[test]
+    fn test_label_table_base_org() {
+        // ラベルは先頭アドレスを足した値になり、.org はセクションの先頭からの位置まで進める
+        let s: &str = "reset:\nj start\n.org 0x10\ntrap:\nnop\nstart:\n.data\nv:\n.org 8\nw:\n";
+        let mut bases = HashMap::new();
+        bases.insert(TEXT_SECTION.to_string(), 0x1000);
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        assert_eq!(lt.value("reset"), Some(0x1000));
+        assert_eq!(lt.value("trap"), Some(0x1010));
+        assert_eq!(lt.value("start"), Some(0x1014));
+        assert_eq!(lt.value("v"), Some(0));
+        assert_eq!(lt.value("w"), Some(8));
+
+        // .org で後ろに戻ることはできない
+        let mut l = Lexer::new("nop\nnop\n.org 4\n");
+        let mut p = Parser::new(&mut l);
+        let e = make_label_table(&mut p).unwrap_err();
+        assert!(e.starts_with("3:1: "), "{}", e);
+        let mut l = Lexer::new("nop\n.org 4\n");
+        let mut p = Parser::new(&mut l);
+        assert!(make_label_table(&mut p).is_ok());
+        // 先頭アドレスより小さい .org でも、先頭からの位置が進んでいればよい
+        bases.insert(TEXT_SECTION.to_string(), 0x8000);
+        let mut l = Lexer::new("nop\n.org 0x100\nend:\n");
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table_with_bases(&mut p, &bases).unwrap();
+        assert_eq!(lt.value("end"), Some(0x8100));
+        let mut l = Lexer::new("nop\nnop\n.org 4\n");
+        let mut p = Parser::new(&mut l);
+        let e = make_label_table_with_bases(&mut p, &bases).unwrap_err();
+        assert!(e.contains("current offset 0x8"), "{}", e);
+
+        // 先頭アドレスと .org の和が 32bit のアドレス空間を超えるとエラーにする
+        bases.insert(TEXT_SECTION.to_string(), 0xffff_fff0);
+        let mut p = Parser::new(Lexer::new("nop\n.org 0xc\nend:\n.org 0x20\n"));
+        let e = make_label_table_with_bases(&mut p, &bases).unwrap_err();
+        assert!(e.starts_with("4:1: locate: "), "{}", e);
+    }
+
+    #[test]
+    fn test_label_table_symbols() {
+        let s: &str = ".globl main, table\n.extern putc\nmain:\ncall putc\nret\n.Lend:\n.size main, .Lend - main\n.data\n.weak table\ntable:\n.word 1, 2, 3\n.size table, . - table\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+
+        let main = lt.get("main").unwrap();
+        assert_eq!(main.binding, Binding::Global);
+        assert_eq!(main.size, Some(12));
+        assert_eq!(main.section.as_deref(), Some(".text"));
+        assert_eq!((main.loc.line, main.loc.col), (3, 1));
+        // 後から宣言した binding にする
+        let table = lt.get("table").unwrap();
+        assert_eq!((table.binding, table.size), (Binding::Weak, Some(12)));
+        assert_eq!(lt.get(".Lend").unwrap().binding, Binding::Local);
+        // .extern で宣言して定義していないシンボル
+        assert!(lt.is_external("putc"));
+        assert_eq!(lt.value("putc"), None);
+
+        let errors = [
+            // 再定義のエラーは最初に定義した位置を示す
+            (
+                "a:\nnop\na:\n",
+                "3:1: SymbolTable::define: label \"a\" is already defined at 1:1",
+            ),
+            (".size a, 4\n", "1:1: SymbolTable::set_size"),
+            (
+                "a:\n.size a, b - a\n",
+                "2:1: make_label_table: label \"b\" is not defined",
+            ),
+            (
+                "a:\n.data\nb:\n.size a, b - a\n",
+                "4:1: make_label_table: size cannot",
+            ),
+            (
+                "b:\nnop\na:\n.size a, b - a\n",
+                "4:1: make_label_table: size -4",
+            ),
+        ];
+        for (s, e) in errors.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let err = make_label_table(&mut p).unwrap_err();
+            assert!(err.starts_with(e), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_label_table_section() {
+        // セクションごとに位置カウンタを持ち、切り替えても続きから数える
+        let s: &str = "a:\nnop\n.data\nb:\nnop\n.section .text.init\nc:\n.section .sdata\nd:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let (_, lt) = make_label_table(&mut p).unwrap();
+        assert_eq!(lt.value("a"), Some(0));
+        assert_eq!(lt.value("b"), Some(0));
+        assert_eq!(lt.value("c"), Some(4));
+        assert_eq!(lt.value("d"), Some(4));
+
+        let section = |l| lt.get(l).and_then(|sym| sym.section.as_deref());
+        assert_eq!(section("a"), Some(".text"));
+        assert_eq!(section("b"), Some(".data"));
+        assert_eq!(section("c"), Some(".text"));
+        assert_eq!(section("d"), Some(".data"));
+    }
+
+    #[test]
+    fn test_label_table_place_data_sections() {
+        // 配置していない .text 以外のセクションは、データのアドレス空間に現れた順に詰めて置く
+        let s: &str =
+            "nop\n.data\na:\n.byte 1\n.rodata\n.p2align 3\nb:\n.word 2\n.bss\nc:\n.space 4\n";
+        let a = parse_all(&mut Parser::new(Lexer::new(s))).unwrap();
+        let mut fixed = HashMap::new();
+        fixed.insert(TEXT_SECTION.to_string(), 0x1000);
+        let bases = place_data_sections(&a, &fixed).unwrap();
+        assert_eq!(bases.get(TEXT_SECTION), Some(&0x1000));
+        assert_eq!(bases.get(".data"), Some(&0));
+        assert_eq!(bases.get(".rodata"), Some(&8));
+        assert_eq!(bases.get(BSS_SECTION), Some(&0xc));
+        let lt = collect_labels(&a, &bases).unwrap();
+        assert_eq!(lt.value("a"), Some(0));
+        assert_eq!(lt.value("b"), Some(8));
+        assert_eq!(lt.value("c"), Some(0xc));
+
+        // 配置したセクションはそのまま
+        fixed.insert(".rodata".to_string(), 0x100);
+        let bases = place_data_sections(&a, &fixed).unwrap();
+        assert_eq!(bases.get(".rodata"), Some(&0x100));
+        assert_eq!(bases.get(BSS_SECTION), Some(&4));
+    }
+
+    #[test]
+    fn test_resolve_local_label() {
+        let mut local_labels = HashMap::new();
+        local_labels.insert("1".to_string(), 2);
+        assert_eq!(
+            resolve_local_label("1b", &local_labels),
+            Some(Ok(local_label_key("1", 1)))
+        );
+        assert_eq!(
+            resolve_local_label("1f", &local_labels),
+            Some(Ok(local_label_key("1", 2)))
+        );
+        assert!(resolve_local_label("2b", &local_labels).unwrap().is_err());
+        assert_eq!(resolve_local_label("loop", &local_labels), None);
+        assert_eq!(resolve_local_label("b", &local_labels), None);
+    }
 }
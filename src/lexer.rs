@@ -1,60 +1,159 @@
+use std::rc::Rc;
+
 use crate::token::*;
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Lexer<'a> {
-    input: &'a [u8],
+    input: &'a str,
     pos: usize,
     next_pos: usize,
-    ch: u8,
+    ch: char,
+    // 現在の文字の行番号と列番号
+    line: usize,
+    col: usize,
+    // 読み取っているファイル
+    file: Option<Rc<SourceFile>>,
 }
 
 impl<'a> Lexer<'a> {
-    const EOF_CONST: u8 = 0;
+    const EOF_CONST: char = '\0';
     // Lexer のコンストラクター
     pub fn new(input: &'a str) -> Self {
-        let input = input.as_bytes();
         let mut l = Self {
-            input: input,
+            input,
             pos: 0,
             next_pos: 0,
-            ch: 0,
+            ch: Self::EOF_CONST,
+            line: 1,
+            col: 0,
+            file: None,
         };
         l.read_char();
         l
     }
 
+    // ファイルから読み取った input の Lexer を作る
+    // 各 token の位置にファイルを記録する
+    pub fn with_file(input: &'a str, file: SourceFile) -> Self {
+        let mut l = Self::new(input);
+        l.file = Some(Rc::new(file));
+        l
+    }
+
     // 次のトークンを返すメソッド
     // 現在の文字を検査して、次の文字をせっとしてから返す
     pub fn next_token(&mut self) -> Token {
+        // 空白を読み飛ばす
+        self.skip_whitespaces();
+
         let mut tok = Token {
             kind: TokenKind::EOF,
+            loc: Loc {
+                file: self.file.clone(),
+                line: self.line,
+                col: self.col,
+                expansion: None,
+            },
         };
 
-        // 空白を読み飛ばす
-        self.skip_whitespaces();
-
         // Tokenを取り出す
         match self.ch {
-            b'\r' | b'\n' => tok.kind = TokenKind::NewLine,
-            b',' => tok.kind = TokenKind::Comma,
-            b'(' => tok.kind = TokenKind::LParen,
-            b')' => tok.kind = TokenKind::RParen,
-            b':' => tok.kind = TokenKind::Colon,
+            '\r' | '\n' => tok.kind = TokenKind::NewLine,
+            ',' => tok.kind = TokenKind::Comma,
+            '(' => tok.kind = TokenKind::LParen,
+            ')' => tok.kind = TokenKind::RParen,
+            ':' => tok.kind = TokenKind::Colon,
+            '=' if self.peek_char() == Some('=') => {
+                self.read_char();
+                tok.kind = TokenKind::EqEq
+            }
+            '=' => tok.kind = TokenKind::Equal,
+            '!' if self.peek_char() == Some('=') => {
+                self.read_char();
+                tok.kind = TokenKind::Ne
+            }
+            '!' => tok.kind = TokenKind::Bang,
+            '+' => tok.kind = TokenKind::Plus,
+            '-' if !self.is_digit_next() => tok.kind = TokenKind::Minus,
+            '*' => tok.kind = TokenKind::Star,
+            '/' => tok.kind = TokenKind::Slash,
+            '%' if !self.is_letter_next() => tok.kind = TokenKind::Percent,
+            '&' if self.peek_char() == Some('&') => {
+                self.read_char();
+                tok.kind = TokenKind::AndAnd
+            }
+            '&' => tok.kind = TokenKind::Amp,
+            '|' if self.peek_char() == Some('|') => {
+                self.read_char();
+                tok.kind = TokenKind::OrOr
+            }
+            '|' => tok.kind = TokenKind::Pipe,
+            '^' => tok.kind = TokenKind::Caret,
+            '~' => tok.kind = TokenKind::Tilde,
+            '@' => tok.kind = TokenKind::At,
+            // .word のようなディレクティブや .Lfoo のような識別子でない . は現在のアドレス
+            '.' if !self.is_ident_start_next() => tok.kind = TokenKind::Dot,
+            '<' if self.peek_char() == Some('<') => {
+                self.read_char();
+                tok.kind = TokenKind::Shl
+            }
+            '<' if self.peek_char() == Some('=') => {
+                self.read_char();
+                tok.kind = TokenKind::Le
+            }
+            '<' => tok.kind = TokenKind::Lt,
+            '>' if self.peek_char() == Some('>') => {
+                self.read_char();
+                tok.kind = TokenKind::Shr
+            }
+            '>' if self.peek_char() == Some('=') => {
+                self.read_char();
+                tok.kind = TokenKind::Ge
+            }
+            '>' => tok.kind = TokenKind::Gt,
+            '"' => {
+                tok.kind = match self.read_string() {
+                    Ok(s) => TokenKind::Str(s),
+                    Err(e) => TokenKind::Error(e),
+                };
+                return tok;
+            }
             Self::EOF_CONST => tok.kind = TokenKind::EOF,
             _ => {
-                if self.is_digit() || self.is_minus_lit() {
-                    let s = String::from_utf8(self.read_number().to_vec()).unwrap();
-                    let n = s.parse::<isize>().unwrap();
-                    tok.kind = TokenKind::Number(n);
+                if self.is_digit() || (self.is_minus_lit() && self.is_digit_next()) {
+                    let s = self.read_number().to_string();
+                    // 1b, 1f のような数字ラベルの参照はシンボルとして扱う
+                    if !s.starts_with('-') && self.is_local_label_suffix() {
+                        tok.kind = TokenKind::Symbol(format!("{}{}", s, self.ch));
+                        self.read_char();
+                        return tok;
+                    }
+                    tok.kind = match parse_number(&s) {
+                        Some(n) => TokenKind::Number(n),
+                        None => TokenKind::ILEGAL,
+                    };
                     return tok;
-                } else if self.is_letter() {
-                    let ident = String::from_utf8(self.read_identifier().to_vec()).unwrap();
+                } else if self.ch == '%' && self.is_letter_next() {
+                    let op = self.read_reloc_operator().to_string();
+                    tok.kind = TokenKind::Reloc(op);
+                    return tok;
+                } else if self.is_ident_start()
+                    || self.ch == '\\'
+                    || (self.ch == '.' && self.is_ident_start_next())
+                {
+                    let ident = self.read_identifier().to_string();
                     if let Some(kind) = lookup_keyword(&ident) {
                         tok.kind = kind;
                     } else {
                         tok.kind = TokenKind::Symbol(ident);
                     }
                     return tok;
+                } else if let Some(c) = ascii_equivalent(self.ch) {
+                    // IME で入力された全角文字
+                    tok.kind = TokenKind::Error(format!(
+                        "Lexer::next_token: full-width character '{}' (U+{:04X}), did you mean '{}'?",
+                        self.ch, self.ch as u32, c
+                    ));
                 } else {
                     tok.kind = TokenKind::ILEGAL
                 }
@@ -66,38 +165,183 @@ impl<'a> Lexer<'a> {
 
     // 次の文字を読み込む
     fn read_char(&mut self) {
-        if self.next_pos >= self.input.len() {
-            self.ch = Self::EOF_CONST; // EOFを表す定数
+        if self.ch == '\n' {
+            self.line += 1;
+            self.col = 1;
         } else {
-            self.ch = self.input[self.next_pos];
+            self.col += 1;
         }
 
+        // pos と next_pos は input のバイト位置、col は文字単位で数える
         self.pos = self.next_pos;
-        self.next_pos += 1;
+        match self.input.get(self.pos..).and_then(|s| s.chars().next()) {
+            Some(c) => {
+                self.ch = c;
+                self.next_pos += c.len_utf8();
+            }
+            None => {
+                self.ch = Self::EOF_CONST; // EOFを表す定数
+                self.next_pos += 1;
+            }
+        }
     }
 
     // 次の文字を先読み
-    fn peek_char(&self) -> Option<u8> {
-        if self.next_pos >= self.input.len() {
-            return None;
-        }
-
-        Some(self.input[self.next_pos])
+    fn peek_char(&self) -> Option<char> {
+        self.input.get(self.next_pos..)?.chars().next()
     }
 
     //
 
-    // 空白を読み飛ばす
+    // 空白とコメントを読み飛ばす
+    // '#' から行末まではコメントとして扱う
     fn skip_whitespaces(&mut self) {
-        while self.is_whitespace() {
-            self.read_char()
+        loop {
+            if self.is_whitespace() {
+                self.read_char()
+            } else if self.ch == '#' {
+                while !matches!(self.ch, '\r' | '\n' | Self::EOF_CONST) {
+                    self.read_char()
+                }
+            } else {
+                break;
+            }
         }
     }
 
     // Identifierを読み取るメソッド
-    fn read_identifier(&mut self) -> &[u8] {
+    // 先頭は英字, '_', '$', '.' で、二文字目以降は数字と '.' も使える
+    // (.equ, .Lfoo, loop_1, fence.i など)
+    // マクロ本体の \arg, \@, \() も識別子の一部として読み取る
+    fn read_identifier(&mut self) -> &str {
+        let pos = self.pos;
+        if self.ch == '.' {
+            self.read_char();
+        }
+        while self.is_ident_continue() || self.ch == '\\' {
+            if self.ch == '\\' {
+                self.read_char();
+                match self.ch {
+                    '@' => self.read_char(),
+                    '(' if self.peek_char() == Some(')') => {
+                        self.read_char();
+                        self.read_char();
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            self.read_char();
+        }
+
+        &self.input[pos..self.pos]
+    }
+
+    // "..." で囲まれた文字列を読み取るメソッド
+    // \n, \t, \\, \", \xNN, \NNN (8進数) などのエスケープを展開したバイト列を返す
+    // 閉じる '"' がないまま行末に達した場合はエラーを返す
+    fn read_string(&mut self) -> Result<Vec<u8>, String> {
+        self.read_char();
+        let mut bytes = vec![];
+        let mut err = None;
+        loop {
+            match self.ch {
+                '"' => break,
+                '\r' | '\n' | Self::EOF_CONST => {
+                    return Err("Lexer::read_string: unterminated string".to_string())
+                }
+                '\\' => {
+                    self.read_char();
+                    match self.read_escape() {
+                        Ok(Some(b)) => bytes.push(b),
+                        Ok(None) => {}
+                        // 文字列の最後まで読んでから最初のエラーを返す
+                        Err(e) => {
+                            err.get_or_insert(e);
+                        }
+                    }
+                }
+                // UTF-8 の文字はそのままのバイト列にする
+                c => {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    self.read_char();
+                }
+            }
+        }
+        self.read_char();
+        match err {
+            Some(e) => Err(e),
+            None => Ok(bytes),
+        }
+    }
+
+    // '\' の次の文字からエスケープシーケンスを読み取るメソッド
+    // 行末の場合は何も読まずに None を返す
+    fn read_escape(&mut self) -> Result<Option<u8>, String> {
+        let b = match self.ch {
+            '\r' | '\n' | Self::EOF_CONST => return Ok(None),
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0c,
+            'v' => 0x0b,
+            '\\' => b'\\',
+            '"' => b'"',
+            '\'' => b'\'',
+            // 最大 3 桁の 8 進数
+            '0'..='7' => {
+                let mut n = 0;
+                for _ in 0..3 {
+                    match self.ch.to_digit(8) {
+                        Some(d) => n = n * 8 + d,
+                        None => break,
+                    }
+                    self.read_char();
+                }
+                if n > 0xff {
+                    return Err(format!(
+                        "Lexer::read_escape: octal escape \\{:o} is out of range",
+                        n
+                    ));
+                }
+                return Ok(Some(n as u8));
+            }
+            // 最大 2 桁の 16 進数
+            'x' => {
+                self.read_char();
+                let mut n = None;
+                for _ in 0..2 {
+                    match self.ch.to_digit(16) {
+                        Some(d) => n = Some(n.unwrap_or(0) * 16 + d as u8),
+                        None => break,
+                    }
+                    self.read_char();
+                }
+                return n.map(Some).ok_or_else(|| {
+                    "Lexer::read_escape: \\x used with no following hex digits".to_string()
+                });
+            }
+            c => {
+                self.read_char();
+                return Err(format!(
+                    "Lexer::read_escape: unknown escape sequence \\{}",
+                    c.escape_default()
+                ));
+            }
+        };
+        self.read_char();
+        Ok(Some(b))
+    }
+
+    // %hi や %pcrel_lo のような再配置演算子を読み取るメソッド
+    // 先頭の '%' は含まない
+    fn read_reloc_operator(&mut self) -> &str {
+        self.read_char();
         let pos = self.pos;
-        while self.is_letter() || self.is_digit() {
+        while self.is_letter() {
             self.read_char();
         }
 
@@ -105,16 +349,24 @@ impl<'a> Lexer<'a> {
     }
 
     // 数字を読み取るメソッド
-    // 負の数字にも対応
-    pub fn read_number(&mut self) -> &[u8] {
+    // 負の数字と 0x から始まる16進数にも対応
+    pub fn read_number(&mut self) -> &str {
         let pos = self.pos;
         // 先頭が '-' の時は一文字読み飛ばす
         if self.is_minus_lit() {
             self.read_char();
         }
 
-        while self.is_digit() {
-            self.read_char()
+        if self.ch == '0' && matches!(self.peek_char(), Some('x') | Some('X')) {
+            self.read_char();
+            self.read_char();
+            while self.ch.is_ascii_hexdigit() {
+                self.read_char()
+            }
+        } else {
+            while self.is_digit() {
+                self.read_char()
+            }
         }
 
         &self.input[pos..self.pos]
@@ -122,30 +374,80 @@ impl<'a> Lexer<'a> {
 
     // 空白を判定するメソッド
     fn is_whitespace(&self) -> bool {
-        self.ch as char == ' ' || self.ch as char == '\t'
-    }
-
-    // 改行を判定するメソッド
-    fn is_newline(&self) -> bool {
-        self.ch as char == '\r' || self.ch as char == '\n'
+        self.ch == ' ' || self.ch == '\t'
     }
 
     // 文字を判定するメソッド
+    // UART_BASE のような定数名のために '_' も文字として扱う
     fn is_letter(&self) -> bool {
-        (b'a' <= self.ch && self.ch <= b'z') || (b'A' <= self.ch && self.ch <= b'Z')
+        self.ch.is_ascii_alphabetic() || self.ch == '_'
     }
 
     // 数字を判定するメソッド
     fn is_digit(&self) -> bool {
-        b'0' <= self.ch && self.ch <= b'9'
+        self.ch.is_ascii_digit()
+    }
+
+    // 次の文字が数字かを判定するメソッド
+    fn is_digit_next(&self) -> bool {
+        matches!(self.peek_char(), Some(c) if c.is_ascii_digit())
+    }
+
+    // 次の文字が英字かを判定するメソッド
+    fn is_letter_next(&self) -> bool {
+        matches!(self.peek_char(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+    }
+
+    // 識別子の先頭の文字かを判定するメソッド
+    fn is_ident_start(&self) -> bool {
+        self.is_letter() || self.ch == '$'
+    }
+
+    // 次の文字が識別子の先頭の文字かを判定するメソッド
+    fn is_ident_start_next(&self) -> bool {
+        matches!(self.peek_char(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$')
+    }
+
+    // 識別子の二文字目以降の文字かを判定するメソッド
+    fn is_ident_continue(&self) -> bool {
+        self.is_ident_start() || self.is_digit() || self.ch == '.'
+    }
+
+    // 数字ラベルの参照の接尾辞 'b', 'f' を判定するメソッド
+    fn is_local_label_suffix(&self) -> bool {
+        (self.ch == 'b' || self.ch == 'f')
+            && !matches!(self.peek_char(), Some(c) if c.is_ascii_alphanumeric())
     }
 
     // '-' を判定するメソッド
     fn is_minus_lit(&mut self) -> bool {
-        self.ch == b'-'
+        self.ch == '-'
     }
 }
 
+// IME で入力しがちな全角文字に対応する ASCII 文字を返す
+// 全角の空白 (U+3000) と、全角の英数字と記号 (U+FF01 ~ U+FF5E)
+fn ascii_equivalent(c: char) -> Option<char> {
+    match c {
+        '\u{3000}' => Some(' '),
+        '\u{ff01}'..='\u{ff5e}' => std::char::from_u32(c as u32 - 0xfee0),
+        _ => None,
+    }
+}
+
+// 10進数または16進数の文字列を数値に変換する
+fn parse_number(s: &str) -> Option<isize> {
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => isize::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<isize>().ok()?,
+    };
+    Some(if neg { -n } else { n })
+}
+
 impl<'a> Iterator for Lexer<'a> {
     type Item = Token;
     // EOF の代わりにNoneを返す
@@ -159,19 +461,16 @@ impl<'a> Iterator for Lexer<'a> {
 
 #[cfg(test)]
 mod lexer_tests {
-    use crate::{
-        lexer::Lexer,
-        token::{Token, TokenKind},
-    };
+    use crate::{lexer::Lexer, token::TokenKind};
 
     #[test]
     fn test_read_char() {
         let s = "lw 6, 10(5)";
         let mut l = Lexer::new(s);
 
-        assert_eq!(l.ch as char, 'l');
+        assert_eq!(l.ch, 'l');
         l.read_char();
-        assert_eq!(l.ch as char, 'w');
+        assert_eq!(l.ch, 'w');
     }
 
     #[test]
@@ -179,8 +478,8 @@ mod lexer_tests {
         let s = "lw 6, 10(5)";
         let l = Lexer::new(s);
 
-        assert_eq!(l.ch as char, 'l');
-        assert_eq!(l.peek_char().unwrap() as char, 'w');
+        assert_eq!(l.ch, 'l');
+        assert_eq!(l.peek_char().unwrap(), 'w');
     }
 
     #[test]
@@ -190,28 +489,28 @@ mod lexer_tests {
         l.read_char();
         l.read_char();
         l.skip_whitespaces();
-        assert_eq!(l.ch as char, '6')
+        assert_eq!(l.ch, '6')
     }
 
     #[test]
     fn test_read_identifier() {
         let s = "lw 6, 10(5)";
         let mut l = Lexer::new(s);
-        assert_eq!(l.read_identifier(), b"lw");
+        assert_eq!(l.read_identifier(), "lw");
     }
 
     #[test]
     fn test_read_number1() {
         let s = "6888, 10(5)";
         let mut l = Lexer::new(s);
-        assert_eq!(l.read_number(), b"6888");
+        assert_eq!(l.read_number(), "6888");
     }
 
     #[test]
     fn test_read_number2() {
         let s = "-8";
         let mut l = Lexer::new(s);
-        assert_eq!(l.read_number(), b"-8");
+        assert_eq!(l.read_number(), "-8");
     }
 
     #[test]
@@ -620,4 +919,329 @@ mod lexer_tests {
         assert_eq!(l.next_token().kind, TokenKind::Colon);
         assert_eq!(l.next_token().kind, TokenKind::EOF);
     }
+
+    #[test]
+    fn test_lexer_u_lui_reloc() {
+        let s = "lui a0, %hi(msg)\n";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::LUI);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("a0".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Reloc("hi".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::LParen);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("msg".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::RParen);
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::EOF);
+    }
+
+    #[test]
+    fn test_lexer_reloc_operator() {
+        let s = "%pcrel_lo(1)";
+        let mut l = Lexer::new(s);
+        assert_eq!(
+            l.next_token().kind,
+            TokenKind::Reloc("pcrel_lo".to_string())
+        );
+        assert_eq!(l.next_token().kind, TokenKind::LParen);
+        assert_eq!(l.next_token().kind, TokenKind::Number(1));
+        assert_eq!(l.next_token().kind, TokenKind::RParen);
+    }
+
+    #[test]
+    fn test_lexer_local_label() {
+        let s = "1:\nbne 1, 2, 1b\nbeq 1, 2, 12f\n";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::Number(1));
+        assert_eq!(l.next_token().kind, TokenKind::Colon);
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::BNE);
+        assert_eq!(l.next_token().kind, TokenKind::Number(1));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Number(2));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("1b".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::BEQ);
+        assert_eq!(l.next_token().kind, TokenKind::Number(1));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Number(2));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("12f".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::EOF);
+    }
+
+    #[test]
+    fn test_read_number_hex() {
+        let s = "0x1F, 10(5)";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.read_number(), "0x1F");
+    }
+
+    #[test]
+    fn test_lexer_hex_number() {
+        let s = "0x10000000 -0x20 0xg";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::Number(0x10000000));
+        assert_eq!(l.next_token().kind, TokenKind::Number(-0x20));
+        assert_eq!(l.next_token().kind, TokenKind::ILEGAL);
+    }
+
+    #[test]
+    fn test_lexer_operators() {
+        let s = "(A + 1) * 2 - 3 / 4 % 5 << 6 >> 7 & 8 | 9 ^ ~10";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::LParen);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("A".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::Plus);
+        assert_eq!(l.next_token().kind, TokenKind::Number(1));
+        assert_eq!(l.next_token().kind, TokenKind::RParen);
+        assert_eq!(l.next_token().kind, TokenKind::Star);
+        assert_eq!(l.next_token().kind, TokenKind::Number(2));
+        assert_eq!(l.next_token().kind, TokenKind::Minus);
+        assert_eq!(l.next_token().kind, TokenKind::Number(3));
+        assert_eq!(l.next_token().kind, TokenKind::Slash);
+        assert_eq!(l.next_token().kind, TokenKind::Number(4));
+        assert_eq!(l.next_token().kind, TokenKind::Percent);
+        assert_eq!(l.next_token().kind, TokenKind::Number(5));
+        assert_eq!(l.next_token().kind, TokenKind::Shl);
+        assert_eq!(l.next_token().kind, TokenKind::Number(6));
+        assert_eq!(l.next_token().kind, TokenKind::Shr);
+        assert_eq!(l.next_token().kind, TokenKind::Number(7));
+        assert_eq!(l.next_token().kind, TokenKind::Amp);
+        assert_eq!(l.next_token().kind, TokenKind::Number(8));
+        assert_eq!(l.next_token().kind, TokenKind::Pipe);
+        assert_eq!(l.next_token().kind, TokenKind::Number(9));
+        assert_eq!(l.next_token().kind, TokenKind::Caret);
+        assert_eq!(l.next_token().kind, TokenKind::Tilde);
+        assert_eq!(l.next_token().kind, TokenKind::Number(10));
+        assert_eq!(l.next_token().kind, TokenKind::EOF);
+    }
+
+    #[test]
+    fn test_lexer_dot() {
+        let s = ".size f, .-f\n.Lend - .";
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::SIZE,
+            TokenKind::Symbol("f".to_string()),
+            TokenKind::Comma,
+            TokenKind::Dot,
+            TokenKind::Minus,
+            TokenKind::Symbol("f".to_string()),
+            TokenKind::NewLine,
+            TokenKind::Symbol(".Lend".to_string()),
+            TokenKind::Minus,
+            TokenKind::Dot,
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_comparison_operators() {
+        let s = "== != < <= > >= && || !";
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::EqEq,
+            TokenKind::Ne,
+            TokenKind::Lt,
+            TokenKind::Le,
+            TokenKind::Gt,
+            TokenKind::Ge,
+            TokenKind::AndAnd,
+            TokenKind::OrOr,
+            TokenKind::Bang,
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_conditional() {
+        let s = ".if SIM\n.error \"no board\"\n.endif\n\"open\n";
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::IF,
+            TokenKind::Symbol("SIM".to_string()),
+            TokenKind::NewLine,
+            TokenKind::ERROR,
+            TokenKind::Str(b"no board".to_vec()),
+            TokenKind::NewLine,
+            TokenKind::ENDIF,
+            TokenKind::NewLine,
+            // 閉じていない文字列
+            TokenKind::Error("Lexer::read_string: unterminated string".to_string()),
+            TokenKind::NewLine,
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_string_escape() {
+        let s = r#""a\tb\n" "\\\"\'" "\x41\x7f\xff\x4g" "\0\101\377" "あ" "\q" "\x" "\400" "a\"#;
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::Str(b"a\tb\n".to_vec()),
+            TokenKind::Str(b"\\\"'".to_vec()),
+            TokenKind::Str(vec![0x41, 0x7f, 0xff, 0x04, b'g']),
+            TokenKind::Str(vec![0, 0o101, 0o377]),
+            // UTF-8 の文字はそのままのバイト列になる
+            TokenKind::Str("あ".as_bytes().to_vec()),
+            TokenKind::Error("Lexer::read_escape: unknown escape sequence \\q".to_string()),
+            TokenKind::Error(
+                "Lexer::read_escape: \\x used with no following hex digits".to_string(),
+            ),
+            TokenKind::Error("Lexer::read_escape: octal escape \\400 is out of range".to_string()),
+            // 行末の '\' では文字列は閉じない
+            TokenKind::Error("Lexer::read_string: unterminated string".to_string()),
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_identifier() {
+        let s = "loop_1 _start .Lfoo fence.i $tmp a.b$1 ADDI .EQU\n";
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::Symbol("loop_1".to_string()),
+            TokenKind::Symbol("_start".to_string()),
+            TokenKind::Symbol(".Lfoo".to_string()),
+            TokenKind::Symbol("fence.i".to_string()),
+            TokenKind::Symbol("$tmp".to_string()),
+            TokenKind::Symbol("a.b$1".to_string()),
+            // 命令とディレクティブは大文字と小文字を区別しない
+            TokenKind::ADDI,
+            TokenKind::EQU,
+            TokenKind::NewLine,
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_hash_comment() {
+        let s = "# %bb.0:\ncall f@plt # comment\n";
+        let l = Lexer::new(s);
+        let kinds = l.map(|tok| tok.kind).collect::<Vec<TokenKind>>();
+        let expect = vec![
+            TokenKind::NewLine,
+            TokenKind::CALL,
+            TokenKind::Symbol("f".to_string()),
+            TokenKind::At,
+            TokenKind::Symbol("plt".to_string()),
+            TokenKind::NewLine,
+            TokenKind::EOF,
+        ];
+        assert_eq!(kinds, expect);
+    }
+
+    #[test]
+    fn test_lexer_equ() {
+        let s = ".equ UART_BASE, 0x10000000\nLED = 0x20\n";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::EQU);
+        assert_eq!(
+            l.next_token().kind,
+            TokenKind::Symbol("UART_BASE".to_string())
+        );
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(l.next_token().kind, TokenKind::Number(0x10000000));
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("LED".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::Equal);
+        assert_eq!(l.next_token().kind, TokenKind::Number(0x20));
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+        assert_eq!(l.next_token().kind, TokenKind::EOF);
+    }
+
+    #[test]
+    fn test_lexer_loc() {
+        let s = "add 1, 2, 3\n  loop:\n";
+        let mut l = Lexer::new(s);
+        let tok = l.next_token();
+        assert_eq!((tok.loc.line, tok.loc.col), (1, 1));
+        let tok = l.next_token();
+        assert_eq!((tok.loc.line, tok.loc.col), (1, 5));
+        for _ in 0..5 {
+            l.next_token();
+        }
+        let tok = l.next_token();
+        assert_eq!(tok.kind, TokenKind::Symbol("loop".to_string()));
+        assert_eq!((tok.loc.line, tok.loc.col), (2, 3));
+    }
+
+    #[test]
+    fn test_lexer_utf8() {
+        // コメントと文字列には任意の文字を使え、列番号は文字単位で数える
+        let s = "# 日本語のコメント\n.error \"エラー\" x # 終わり\n";
+        let toks = Lexer::new(s)
+            .map(|tok| (tok.kind, tok.loc.line, tok.loc.col))
+            .collect::<Vec<_>>();
+        let expect = vec![
+            (TokenKind::NewLine, 1, 11),
+            (TokenKind::ERROR, 2, 1),
+            (TokenKind::Str("エラー".as_bytes().to_vec()), 2, 8),
+            (TokenKind::Symbol("x".to_string()), 2, 14),
+            (TokenKind::NewLine, 2, 21),
+            (TokenKind::EOF, 3, 1),
+        ];
+        assert_eq!(toks, expect);
+    }
+
+    #[test]
+    fn test_lexer_full_width() {
+        let s = "addi a0\u{ff0c} a0, 1\u{3000}\u{ff08}x\n";
+        let toks = Lexer::new(s)
+            .map(|tok| (tok.kind, tok.loc.col))
+            .collect::<Vec<_>>();
+        let full_width = |c: &str, code: &str, ascii: &str| {
+            TokenKind::Error(format!(
+                "Lexer::next_token: full-width character '{}' (U+{}), did you mean '{}'?",
+                c, code, ascii
+            ))
+        };
+        let expect = vec![
+            (TokenKind::ADDI, 1),
+            (TokenKind::Symbol("a0".to_string()), 6),
+            (full_width("\u{ff0c}", "FF0C", ","), 8),
+            (TokenKind::Symbol("a0".to_string()), 10),
+            (TokenKind::Comma, 12),
+            (TokenKind::Number(1), 14),
+            (full_width("\u{3000}", "3000", " "), 15),
+            (full_width("\u{ff08}", "FF08", "("), 16),
+            (TokenKind::Symbol("x".to_string()), 17),
+            (TokenKind::NewLine, 18),
+            (TokenKind::EOF, 1),
+        ];
+        assert_eq!(toks, expect);
+        // その他の ASCII 以外の文字
+        assert_eq!(Lexer::new("é").next_token().kind, TokenKind::ILEGAL);
+    }
+
+    #[test]
+    fn test_lexer_macro_arg() {
+        let s = "\\reg, loop\\@, \\name\\()_end\n";
+        let mut l = Lexer::new(s);
+        assert_eq!(l.next_token().kind, TokenKind::Symbol("\\reg".to_string()));
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(
+            l.next_token().kind,
+            TokenKind::Symbol("loop\\@".to_string())
+        );
+        assert_eq!(l.next_token().kind, TokenKind::Comma);
+        assert_eq!(
+            l.next_token().kind,
+            TokenKind::Symbol("\\name\\()_end".to_string())
+        );
+        assert_eq!(l.next_token().kind, TokenKind::NewLine);
+    }
 }
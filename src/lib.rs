@@ -1,8 +1,12 @@
 pub mod asm;
 pub mod assembler;
 pub mod code_gen;
+pub mod constant_table;
 pub mod inst;
 pub mod label_table;
 pub mod lexer;
+pub mod memory_map;
 pub mod parser;
+pub mod preprocessor;
+pub mod symbol_table;
 pub mod token;
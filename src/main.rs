@@ -1,30 +1,129 @@
+use kas_riscv::asm::ADDRESS_SPACE;
 use kas_riscv::assembler::Assembler;
+use kas_riscv::constant_table::ConstantTable;
 // use kas_riscv::assembler::{assemble_bin, assemble_hex};
-use kas_riscv::code_gen::gen_hex;
-use kas_riscv::label_table::make_label_table;
+use kas_riscv::label_table::{collect_labels, parse_all, place_data_sections, TEXT_SECTION};
 use kas_riscv::lexer::Lexer;
+use kas_riscv::memory_map::MemoryMap;
 use kas_riscv::parser::Parser;
+use kas_riscv::preprocessor::Preprocessor;
+use kas_riscv::token::SourceFile;
 
+use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
 use std::fs::*;
 use std::path::Path;
 use std::process;
 
-use std::io::{BufReader, Read, Write};
-
-use kas_riscv::lexer::*;
+use std::io::Write;
 
 fn main() {
     let args = env::args().collect::<Vec<String>>();
-    if args.len() < 2 {
-        eprintln!("please input file!");
-        process::exit(1);
+
+    // -D NAME=VALUE で定義された定数、-I DIR の検索パスと入力ファイルを読み取る
+    let mut constants = ConstantTable::new();
+    let mut include_dirs = vec![];
+    let mut gnu_compat = false;
+    let mut base = None;
+    let mut memory = None;
+    let mut input_file = None;
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        // --gnu は gcc -S の出力を読み込むための GNU as 互換モード
+        if arg == "--gnu" {
+            gnu_compat = true;
+            continue;
+        }
+        // --base ADDR または --base=ADDR は .text の先頭アドレス
+        if arg == "--base" || arg.starts_with("--base=") {
+            base = match arg.strip_prefix("--base=") {
+                Some(value) => Some(value),
+                None => iter.next().map(String::as_str),
+            };
+            if base.is_none() {
+                eprintln!("option --base requires an argument");
+                process::exit(1);
+            }
+            continue;
+        }
+        // --memory FILE はメモリ領域の定義ファイル
+        if arg == "--memory" || arg.starts_with("--memory=") {
+            memory = match arg.strip_prefix("--memory=") {
+                Some(value) => Some(value),
+                None => iter.next().map(String::as_str),
+            };
+            if memory.is_none() {
+                eprintln!("option --memory requires an argument");
+                process::exit(1);
+            }
+            continue;
+        }
+        // オプションの値は続けて書いても (-DSIM)、次の引数に書いてもよい (-D SIM)
+        let (opt, value) = match arg.get(..2) {
+            Some(opt @ ("-D" | "-I")) => match &arg[2..] {
+                "" => (opt, iter.next().map(String::as_str)),
+                value => (opt, Some(value)),
+            },
+            _ => {
+                input_file = Some(arg);
+                continue;
+            }
+        };
+        let value = match value {
+            Some(value) => value,
+            None => {
+                eprintln!("option {} requires an argument", opt);
+                process::exit(1);
+            }
+        };
+        if opt == "-I" {
+            include_dirs.push(value);
+        } else if let Err(e) = parse_define(value, &mut constants) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
     }
+    let input_file = match input_file {
+        Some(input_file) => input_file,
+        None => {
+            eprintln!("please input file!");
+            process::exit(1);
+        }
+    };
 
-    let input_file_path = Path::new(&args[1]);
+    // --base で指定された .text の先頭アドレス
+    let base = base.map(|base| match parse_base(base, &constants) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    });
+    // --memory で指定されたメモリ領域
+    let mut map = match memory {
+        Some(path) => match read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|src| MemoryMap::parse(&src))
+        {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(1);
+            }
+        },
+        None => MemoryMap::new(),
+    };
+
+    let input_file_path = Path::new(input_file);
     let output_file_path = match input_file_path.extension().and_then(OsStr::to_str) {
         Some("kas") => input_file_path.with_extension("hex"),
+        // gcc -S の出力
+        Some("s") if gnu_compat => input_file_path.with_extension("hex"),
+        _ if gnu_compat => {
+            eprintln!("file extension is not *.kas or *.s !");
+            process::exit(1);
+        }
         _ => {
             eprintln!("file extension is not *.kas !");
             process::exit(1);
@@ -34,32 +133,172 @@ fn main() {
     let input = match read_to_string(input_file_path) {
         Ok(src) => src,
         Err(e) => {
-            eprintln!("{}", e.to_string());
+            eprintln!("{}", e);
             process::exit(1);
         }
     };
-    let mut l = Lexer::new(&input);
-    let mut p = Parser::new(&mut l);
-    let (a, lt) = make_label_table(&mut p).unwrap();
-    let mut a = Assembler::new(a, lt);
-    let output = match a.assemble_all() {
-        Ok(src) => src,
+    let mut l = Lexer::with_file(&input, SourceFile::new(input_file_path));
+    let mut pp = Preprocessor::with_constants(constants.clone());
+    for dir in include_dirs {
+        pp.add_include_dir(dir);
+    }
+    pp.set_gnu_compat(gnu_compat);
+    let tokens = match pp.process(&mut l) {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    for w in pp.warnings() {
+        eprintln!("{}", w);
+    }
+    let mut p = Parser::with_constants(tokens.into_iter(), constants);
+    let a = match parse_all(&mut p) {
+        Ok(a) => a,
         Err(e) => {
             eprintln!("{}", e);
             process::exit(1);
         }
     };
+    if let Err(e) = map.add_from(&a) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
 
-    match File::create(output_file_path) {
-        Ok(mut output_file) => {
-            if let Err(e) = write!(output_file, "{}", output) {
-                eprintln!("{}", e.to_string());
+    // メモリ領域があればセクションを配置する
+    // --base で指定した .text はそのアドレスに置き、同じ領域の他のセクションはその後ろに詰める
+    let mut fixed = HashMap::new();
+    if let Some(base) = base {
+        fixed.insert(TEXT_SECTION.to_string(), base);
+    }
+    let placed = if map.is_empty() {
+        fixed
+    } else {
+        match map.place(&a, &fixed) {
+            Ok(placed) => placed,
+            Err(e) => {
+                eprintln!("{}", e);
                 process::exit(1);
             }
         }
+    };
+    // 配置していない .data などのセクションは、データのアドレス空間に詰めて置く
+    let bases = match place_data_sections(&a, &placed) {
+        Ok(bases) => bases,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let lt = match collect_labels(&a, &bases) {
+        Ok(lt) => lt,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    // 領域からあふれたセクションがあればエラーにする
+    let usage = if map.is_empty() {
+        vec![]
+    } else {
+        match map.usage(&a, &bases) {
+            Ok(usage) => usage,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    };
+    let mut a = Assembler::with_layout(a, lt, bases, placed.into_keys().collect());
+    let output = match a.assemble_all() {
+        Ok(src) => src,
         Err(e) => {
             eprintln!("{}", e);
             process::exit(1);
         }
     };
+
+    // .text は input.hex に、それ以外のセクションは input.data.hex のように別のファイルに書き出す
+    let mut files = output
+        .into_iter()
+        .map(|(section, src)| match section.as_str() {
+            TEXT_SECTION => (output_file_path.clone(), src),
+            name => (
+                output_file_path.with_extension(format!("{}.hex", name.trim_start_matches('.'))),
+                src,
+            ),
+        })
+        .collect::<Vec<_>>();
+    // .globl などで他のファイルと関係するシンボルがあれば、シンボル表を input.sym に、
+    // 他のファイルで定義されるシンボルの参照を input.reloc に書き出す
+    if a.symbols().has_global() {
+        files.push((
+            output_file_path.with_extension("sym"),
+            a.symbols().to_string(),
+        ));
+    }
+    if !a.relocations().is_empty() {
+        let relocs = a
+            .relocations()
+            .iter()
+            .map(|r| format!("{}\n", r))
+            .collect::<String>();
+        files.push((output_file_path.with_extension("reloc"), relocs));
+    }
+    for (path, src) in files {
+        match File::create(path) {
+            Ok(mut output_file) => {
+                if let Err(e) = write!(output_file, "{}", src) {
+                    eprintln!("{}", e);
+                    process::exit(1);
+                }
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
+    }
+
+    // 領域ごとの使用量を表示する
+    for u in usage {
+        println!("{}", u);
+    }
+}
+
+// --base ADDR のアドレスを読み取る
+// ADDR には -D で定義した定数を使った式も書ける
+fn parse_base(base: &str, constants: &ConstantTable) -> Result<isize, String> {
+    let mut p = Parser::with_constants(Lexer::new(base), constants.clone());
+    let addr = p
+        .parse_const_expr()
+        .map_err(|e| format!("--base {}: {}", base, e))?;
+    if !(0..ADDRESS_SPACE).contains(&addr) {
+        return Err(format!(
+            "--base {}: address must be in 0..={:#x}",
+            base,
+            ADDRESS_SPACE - 1
+        ));
+    }
+    Ok(addr)
+}
+
+// -D NAME=VALUE の形式の定数の定義を読み取る
+// VALUE を省略した場合は 1 とする
+fn parse_define(define: &str, constants: &mut ConstantTable) -> Result<(), String> {
+    let (name, value) = match define.split_once('=') {
+        Some((name, value)) => {
+            let mut p = Parser::new(Lexer::new(value));
+            let value = p
+                .parse_const_expr()
+                .map_err(|e| format!("-D {}: {}", define, e))?;
+            (name, value)
+        }
+        None => (define, 1),
+    };
+    if name.is_empty() {
+        return Err(format!("-D {}: missing symbol name", define));
+    }
+    constants.define(name, value, false)
 }
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::asm::{output_section, Asm, AsmKind::*};
+use crate::label_table::{locate, section_align, section_extents, TEXT_SECTION};
+
+// メモリの一つの領域
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub origin: isize,
+    pub length: isize,
+    // この領域に置くセクション
+    // どの領域にも書かれていないセクションは、.text を最初の領域に、それ以外を二番目の領域に置く
+    pub sections: Vec<String>,
+}
+
+// ROM: 0x0000, 4K; RAM: 0x1000, 2K のようなメモリの構成
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+// 領域の使用量
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    pub name: String,
+    pub origin: isize,
+    pub length: isize,
+    pub used: isize,
+}
+
+impl MemoryMap {
+    // MemoryMapのコンストラクター
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // "ROM: 0x0000, 4K, .text; RAM: 0x1000, 2K" のような記述を読み取る
+    // 領域は ; または改行で区切り、# から行末まではコメント
+    // 各領域は 名前: 先頭アドレス, 大きさ[, セクション...] で、大きさには K, M をつけられる
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut map = Self::new();
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            for entry in line.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+                map.add(parse_region(entry)?)?;
+            }
+        }
+        if map.is_empty() {
+            return Err("MemoryMap::parse: no memory region is defined".to_string());
+        }
+        Ok(map)
+    }
+
+    // 命令列の .memory で定義された領域を追加する
+    pub fn add_from(&mut self, a: &[Asm]) -> Result<(), String> {
+        for asm in a.iter() {
+            if let MEMORY { map } = &asm.kind {
+                for region in map.regions.iter() {
+                    self.add(region.clone())
+                        .map_err(|e| format!("{}: {}", asm.loc, e))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 領域を追加する
+    // 同じ名前の領域は定義できない
+    pub fn add(&mut self, region: Region) -> Result<(), String> {
+        if self.regions.iter().any(|r| r.name == region.name) {
+            return Err(format!(
+                "MemoryMap::add: memory region {} is already defined",
+                region.name
+            ));
+        }
+        self.regions.push(region);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    // セクションを置く領域の番号を返す
+    fn region_index(&self, section: &str) -> Option<usize> {
+        let listed = self
+            .regions
+            .iter()
+            .position(|r| r.sections.iter().any(|s| s == section));
+        match listed {
+            Some(i) => Some(i),
+            None if self.regions.is_empty() => None,
+            None if section == TEXT_SECTION => Some(0),
+            None => Some(1.min(self.regions.len() - 1)),
+        }
+    }
+
+    // セクションを領域に並べ、各セクションの先頭アドレスを返す
+    // 同じ領域のセクションは現れた順に詰めて置き、先頭はセクション内の .align の最大値 (最低 4) に揃える
+    // fixed は --base のように先頭アドレスを指定したセクションで、そのアドレスに置いて後ろのセクションをその後に詰める
+    pub fn place(
+        &self,
+        a: &[Asm],
+        fixed: &HashMap<String, isize>,
+    ) -> Result<HashMap<String, isize>, String> {
+        let locs = locate(a, &HashMap::new())?;
+        let mut cursors = self.regions.iter().map(|r| r.origin).collect::<Vec<_>>();
+        let mut bases = fixed.clone();
+        for (section, _, _) in section_extents(a, &HashMap::new())? {
+            let i = match self.region_index(&section) {
+                Some(i) => i,
+                None => continue,
+            };
+            if let Some(base) = fixed.get(&section) {
+                let end = section_extents(a, &bases)?
+                    .into_iter()
+                    .find(|(s, _, _)| *s == section)
+                    .map_or(*base, |(_, _, end)| end);
+                cursors[i] = cursors[i].max(end);
+                continue;
+            }
+            let align = section_align(a, &locs, &section);
+            let base = (cursors[i] + align - 1) / align * align;
+            bases.insert(section.clone(), base);
+
+            // .align があるとセクションの大きさは先頭アドレスで変わるので、置いてから計算する
+            let end = section_extents(a, &bases)?
+                .into_iter()
+                .find(|(s, _, _)| *s == section)
+                .map_or(base, |(_, _, end)| end);
+            cursors[i] = end;
+        }
+        Ok(bases)
+    }
+
+    // 領域ごとの使用量を返す
+    // セクションが領域に収まらない場合は、はみ出したバイト数をエラーにする
+    // セクションを置いた領域どうしや、セクションどうしが重なっている場合もエラーにする
+    pub fn usage(&self, a: &[Asm], bases: &HashMap<String, isize>) -> Result<Vec<Usage>, String> {
+        let mut usage = self
+            .regions
+            .iter()
+            .map(|r| Usage {
+                name: r.name.clone(),
+                origin: r.origin,
+                length: r.length,
+                used: 0,
+            })
+            .collect::<Vec<_>>();
+        // 空でないセクションを (セクション名, 先頭アドレス, 末尾のアドレス) で集める
+        let mut placed = vec![];
+        let mut used_regions = vec![false; self.regions.len()];
+        for (section, start, end) in section_extents(a, bases)? {
+            // 空のセクションはメモリを使わない
+            if start == end {
+                continue;
+            }
+            let i = match self.region_index(&section) {
+                Some(i) => i,
+                None => continue,
+            };
+            used_regions[i] = true;
+            placed.push((section.clone(), start, end));
+            let r = &self.regions[i];
+            let limit = r.origin + r.length;
+            let over = (end - limit).max(r.origin - start);
+            if over > 0 {
+                return Err(format!(
+                    "MemoryMap::usage: section {} ({:#x}..{:#x}) overflows memory region {} ({:#x}..{:#x}) by {} bytes",
+                    section, start, end, r.name, r.origin, limit, over
+                ));
+            }
+            usage[i].used = usage[i].used.max(end - r.origin);
+        }
+
+        let used = self
+            .regions
+            .iter()
+            .zip(used_regions)
+            .filter_map(|(r, used)| used.then_some(r))
+            .collect::<Vec<_>>();
+        for (j, r) in used.iter().enumerate() {
+            for q in used[j + 1..].iter() {
+                if r.origin < q.origin + q.length && q.origin < r.origin + r.length {
+                    return Err(format!(
+                        "MemoryMap::usage: memory region {} ({:#x}..{:#x}) overlaps memory region {} ({:#x}..{:#x})",
+                        r.name,
+                        r.origin,
+                        r.origin + r.length,
+                        q.name,
+                        q.origin,
+                        q.origin + q.length
+                    ));
+                }
+            }
+        }
+
+        // 先頭アドレスの順に並べて、隣のセクションと重なっていないかを確かめる
+        placed.sort_by_key(|(_, start, _)| *start);
+        for pair in placed.windows(2) {
+            let ((s1, start1, end1), (s2, start2, end2)) = (&pair[0], &pair[1]);
+            if start2 < end1 {
+                return Err(format!(
+                    "MemoryMap::usage: section {} ({:#x}..{:#x}) overlaps section {} ({:#x}..{:#x})",
+                    s1, start1, end1, s2, start2, end2
+                ));
+            }
+        }
+        Ok(usage)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<8} {:#010x} {:>8} / {:>8} bytes ({:>5.1}%)",
+            self.name,
+            self.origin,
+            self.used,
+            self.length,
+            self.used as f64 * 100.0 / self.length as f64
+        )
+    }
+}
+
+// 名前: 先頭アドレス, 大きさ[, セクション...] を読み取る
+fn parse_region(entry: &str) -> Result<Region, String> {
+    let (name, rest) = match entry.split_once(':') {
+        Some((name, rest)) if !name.trim().is_empty() => (name.trim(), rest),
+        _ => {
+            return Err(format!(
+                "MemoryMap::parse: expected NAME: ORIGIN, LENGTH, but got {:?}",
+                entry
+            ))
+        }
+    };
+    let fields = rest.split(',').map(str::trim).collect::<Vec<_>>();
+    if fields.len() < 2 {
+        return Err(format!(
+            "MemoryMap::parse: expected NAME: ORIGIN, LENGTH, but got {:?}",
+            entry
+        ));
+    }
+
+    let origin = parse_size(fields[0])?;
+    let length = parse_size(fields[1])?;
+    if length <= 0 {
+        return Err(format!(
+            "MemoryMap::parse: length of memory region {} must be positive",
+            name
+        ));
+    }
+    let mut sections = vec![];
+    for s in fields[2..].iter() {
+        if !s.starts_with('.') {
+            return Err(format!(
+                "MemoryMap::parse: expected section name, but got {:?}",
+                s
+            ));
+        }
+        sections.push(output_section(s));
+    }
+
+    Ok(Region {
+        name: name.to_string(),
+        origin,
+        length,
+        sections,
+    })
+}
+
+// 0x1000, 4096, 4K, 1M のようなアドレスや大きさを読み取る
+fn parse_size(s: &str) -> Result<isize, String> {
+    let (digits, unit) = match s.chars().last() {
+        Some('K' | 'k') => (&s[..s.len() - 1], 1024),
+        Some('M' | 'm') => (&s[..s.len() - 1], 1024 * 1024),
+        _ => (s, 1),
+    };
+    let v = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => isize::from_str_radix(hex, 16),
+        None => digits.parse::<isize>(),
+    };
+    v.ok()
+        .filter(|v| *v >= 0)
+        .and_then(|v| v.checked_mul(unit))
+        .ok_or_else(|| format!("MemoryMap::parse: invalid number {:?}", s))
+}
+
+#[cfg(test)]
+mod memory_map_tests {
+    use std::collections::HashMap;
+
+    use crate::label_table::{collect_labels, parse_all};
+    use crate::lexer::Lexer;
+    use crate::memory_map::*;
+    use crate::parser::Parser;
+
+    fn parse_src(s: &str) -> Vec<Asm> {
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        parse_all(&mut p).unwrap()
+    }
+
+    #[test]
+    fn test_memory_map_parse() {
+        let map = MemoryMap::parse(
+            "ROM: 0x0000, 4K # boot\nRAM: 0x1000, 2k, .data, .sbss; IO: 4096, 0x10",
+        )
+        .unwrap();
+        let region = |name: &str, origin: isize, length: isize, sections: &[&str]| Region {
+            name: name.to_string(),
+            origin,
+            length,
+            sections: sections.iter().map(|s| s.to_string()).collect(),
+        };
+        assert_eq!(
+            map.regions(),
+            &[
+                region("ROM", 0, 4096, &[]),
+                region("RAM", 0x1000, 2048, &[".data", ".bss"]),
+                region("IO", 4096, 16, &[]),
+            ]
+        );
+
+        let errors = [
+            "",
+            "# comment only",
+            "ROM 0, 4K",
+            ": 0, 4K",
+            "ROM: 0",
+            "ROM: 0, 0",
+            "ROM: -1, 4K",
+            "ROM: 0, 4G",
+            "ROM: 0, 4K, text",
+            "ROM: 0, 4K; ROM: 0x1000, 4K",
+        ];
+        for s in errors.iter() {
+            assert!(MemoryMap::parse(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_memory_map_place() {
+        // .text は最初の領域、それ以外は二番目の領域に現れた順に詰めて置く
+        let a =
+            parse_src(".data\n.byte 1\n.rodata\n.p2align 3\n.word 1\n.bss\n.space 4\n.text\nnop\n");
+        let map = MemoryMap::parse("ROM: 0x100, 0xf00; RAM: 0x1000, 2K").unwrap();
+        let bases = map.place(&a, &HashMap::new()).unwrap();
+        assert_eq!(bases.get(".text"), Some(&0x100));
+        assert_eq!(bases.get(".data"), Some(&0x1000));
+        assert_eq!(bases.get(".rodata"), Some(&0x1008));
+        assert_eq!(bases.get(".bss"), Some(&0x100c));
+
+        let usage = map.usage(&a, &bases).unwrap();
+        assert_eq!(usage[0].used, 4);
+        assert_eq!(usage[1].used, 0x10);
+        assert_eq!(
+            usage[0].to_string(),
+            "ROM      0x00000100        4 /     3840 bytes (  0.1%)"
+        );
+
+        // 領域にセクションを指定できる
+        let map =
+            MemoryMap::parse("RAM: 0x1000, 2K, .data, .bss; ROM: 0, 4K, .text, .rodata").unwrap();
+        let bases = map.place(&a, &HashMap::new()).unwrap();
+        assert_eq!(bases.get(".text"), Some(&0));
+        assert_eq!(bases.get(".data"), Some(&0x1000));
+        assert_eq!(bases.get(".rodata"), Some(&0x8));
+        assert_eq!(bases.get(".bss"), Some(&0x1004));
+
+        // ラベルは置いたアドレスになる
+        let a = parse_src(".data\nv:\n.word 1\n.text\nf:\nnop\n");
+        let map = MemoryMap::parse("ROM: 0x200, 4K; RAM: 0x1000, 2K").unwrap();
+        let lt = collect_labels(&a, &map.place(&a, &HashMap::new()).unwrap()).unwrap();
+        assert_eq!(lt.value("f"), Some(0x200));
+        assert_eq!(lt.value("v"), Some(0x1000));
+    }
+
+    #[test]
+    fn test_memory_map_overflow() {
+        let a = parse_src("nop\nnop\nnop\n.data\n.space 9\n");
+        let map = MemoryMap::parse("ROM: 0, 8; RAM: 0x100, 16").unwrap();
+        let e = map
+            .usage(&a, &map.place(&a, &HashMap::new()).unwrap())
+            .unwrap_err();
+        assert!(e.contains("ROM"), "{}", e);
+        assert!(e.ends_with("by 4 bytes"), "{}", e);
+
+        // --base などで領域の外に置いた場合もエラーにする
+        let a = parse_src("nop\n");
+        let mut bases = HashMap::new();
+        bases.insert(".text".to_string(), 0x10);
+        let map = MemoryMap::parse("ROM: 0x20, 8").unwrap();
+        let e = map.usage(&a, &bases).unwrap_err();
+        assert!(e.ends_with("by 16 bytes"), "{}", e);
+    }
+
+    #[test]
+    fn test_memory_map_fixed_base() {
+        // --base で指定した .text の後ろに、同じ領域の他のセクションを詰める
+        let a = parse_src("nop\nnop\n.rodata\n.word 1\n.data\n.word 2\n");
+        let map = MemoryMap::parse("ROM: 0, 4K, .text, .rodata; RAM: 0x1000, 2K").unwrap();
+        let mut fixed = HashMap::new();
+        fixed.insert(".text".to_string(), 0x800);
+        let bases = map.place(&a, &fixed).unwrap();
+        assert_eq!(bases.get(".text"), Some(&0x800));
+        assert_eq!(bases.get(".rodata"), Some(&0x808));
+        assert_eq!(bases.get(".data"), Some(&0x1000));
+        let usage = map.usage(&a, &bases).unwrap();
+        assert_eq!(usage[0].used, 0x80c);
+    }
+
+    #[test]
+    fn test_memory_map_overlap() {
+        let a = parse_src("nop\nnop\n.rodata\n.word 1\n.data\n.word 2\n");
+
+        // セクションどうしの重なり
+        let map = MemoryMap::parse("ROM: 0, 4K, .text, .rodata; RAM: 0x1000, 2K").unwrap();
+        let mut bases = map.place(&a, &HashMap::new()).unwrap();
+        bases.insert(".rodata".to_string(), 0x4);
+        let e = map.usage(&a, &bases).unwrap_err();
+        assert!(
+            e.contains("section .text (0x0..0x8) overlaps section .rodata (0x4..0x8)"),
+            "{}",
+            e
+        );
+
+        // セクションを置いた領域どうしの重なり
+        let map = MemoryMap::parse("ROM: 0, 4K; RAM: 0x800, 2K").unwrap();
+        let e = map
+            .usage(&a, &map.place(&a, &HashMap::new()).unwrap())
+            .unwrap_err();
+        assert!(
+            e.contains("memory region ROM (0x0..0x1000) overlaps memory region RAM"),
+            "{}",
+            e
+        );
+
+        // セクションを置かない領域は重なっていてもよい
+        let map = MemoryMap::parse("ROM: 0, 2K; RAM: 0x800, 2K; IO: 0x800, 16").unwrap();
+        assert!(map
+            .usage(&a, &map.place(&a, &HashMap::new()).unwrap())
+            .is_ok());
+    }
+}
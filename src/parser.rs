@@ -1,47 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 use std::result::Result;
 
-use crate::asm::{self, Asm, AsmKind};
+use crate::asm::{self, hi20, lo12, Asm, AsmKind};
+use crate::constant_table::ConstantTable;
+use crate::memory_map::MemoryMap;
+use crate::symbol_table::Binding;
 
-use crate::lexer::*;
 use crate::token::TokenKind::*;
 use crate::token::*;
 
-#[derive(Debug)]
-pub struct Parser<'a> {
-    l: &'a mut Lexer<'a>,
+pub struct Parser {
+    // すべての token と、次に読む token の位置
+    toks: Rc<Vec<Token>>,
+    pos: usize,
     cur_tok: Token,
     next_tok: Token,
+    // .equ, .set, = で定義された定数
+    constants: ConstantTable,
+    // token 列全体にある定数とラベルの定義
+    defs: Rc<Definitions>,
+    // 前方参照を解決している途中の .equ の名前
+    resolving: Vec<String>,
 }
 
-impl<'a> Parser<'a> {
+// token 列全体にある定数とラベルの定義
+// 定義より前で使われたシンボルの解決と、解決できない場合のエラーの区別に使う
+#[derive(Debug, Default)]
+struct Definitions {
+    // .equ name, expr の expr の位置 (最初の定義だけ)
+    equs: HashMap<String, usize>,
+    // .set または = で定義される名前
+    sets: HashSet<String>,
+    // name: で定義されるラベル
+    labels: HashSet<String>,
+}
+
+impl Definitions {
+    fn new(toks: &[Token]) -> Self {
+        let mut defs = Self::default();
+        let kind = |i: usize| toks.get(i).map(|tok| &tok.kind);
+        for i in 0..toks.len() {
+            match (kind(i), kind(i + 1), kind(i + 2)) {
+                (Some(EQU), Some(Symbol(name)), Some(Comma)) => {
+                    defs.equs.entry(name.clone()).or_insert(i + 3);
+                }
+                (Some(SET), Some(Symbol(name)), _) | (Some(Symbol(name)), Some(Equal), _) => {
+                    defs.sets.insert(name.clone());
+                }
+                (Some(Symbol(name)), Some(Colon), _) => {
+                    defs.labels.insert(name.clone());
+                }
+                _ => {}
+            }
+        }
+        defs
+    }
+}
+
+impl Parser {
     // Parserのコンストラクター
-    pub fn new(l: &'a mut Lexer<'a>) -> Self {
-        let cur_tok = l.next_token();
-        let next_tok = l.next_token();
+    // Lexer またはマクロ展開後の token 列を受け取る
+    pub fn new<I: Iterator<Item = Token>>(l: I) -> Self {
+        Self::with_constants(l, ConstantTable::new())
+    }
+
+    // コマンドラインの -D などで定義済みの定数を持つ Parser を作る
+    // 定義より前で使われた .equ の定数を解決するために、token 列を最初にすべて読み込む
+    pub fn with_constants<I: Iterator<Item = Token>>(l: I, constants: ConstantTable) -> Self {
+        let toks = l.collect::<Vec<_>>();
+        let defs = Definitions::new(&toks);
+        Self::at(Rc::new(toks), 0, constants, Rc::new(defs), vec![])
+    }
+
+    // toks の pos から読む Parser を作る
+    fn at(
+        toks: Rc<Vec<Token>>,
+        pos: usize,
+        constants: ConstantTable,
+        defs: Rc<Definitions>,
+        resolving: Vec<String>,
+    ) -> Self {
+        let tok = |i: usize| toks.get(i).cloned().unwrap_or_else(|| Token::new(EOF));
+        let (cur_tok, next_tok) = (tok(pos), tok(pos + 1));
         Parser {
-            l,
+            toks,
+            pos: pos + 2,
             cur_tok,
             next_tok,
+            constants,
+            defs,
+            resolving,
         }
     }
 
+    // 定数の値を返す
+    // まだ定義されていない .equ の定数は、定義の式をその場で計算する
+    // .set, = の定数は後で値が変わることがあるので、定義より前では使えない
+    fn constant(&self, name: &str) -> Option<isize> {
+        if !self.resolving.is_empty() && self.defs.sets.contains(name) {
+            return None;
+        }
+        if let Some(v) = self.constants.get(name) {
+            return Some(v);
+        }
+        let start = *self.defs.equs.get(name)?;
+        // .equ A, B と .equ B, A のような循環した定義は解決できない
+        if self.resolving.iter().any(|n| n == name) {
+            return None;
+        }
+        let mut resolving = self.resolving.clone();
+        resolving.push(name.to_string());
+        let mut p = Self::at(
+            self.toks.clone(),
+            start,
+            self.constants.clone(),
+            self.defs.clone(),
+            resolving,
+        );
+        let v = p.parse_expr().ok()?;
+        matches!(p.cur_tok.kind, NewLine | EOF).then_some(v)
+    }
+
+    // 定数として値を決められないシンボルのエラーを返す
+    fn unresolved_symbol(&self, name: &str) -> String {
+        if self.defs.labels.contains(name) {
+            format!(
+                "Parser::parse_expr_primary: label {:?} cannot be used as a constant",
+                name
+            )
+        } else if self.defs.equs.contains_key(name) || self.defs.sets.contains(name) {
+            format!(
+                "Parser::parse_expr_primary: {:?} is used before definition",
+                name
+            )
+        } else {
+            format!("Parser::parse_expr_primary: {:?} is not defined", name)
+        }
+    }
+
+    // parse の途中までに定義された定数の表を取り出す
+    pub fn into_constants(self) -> ConstantTable {
+        self.constants
+    }
+
     // 次のtokenをセットするメソッド
     fn next_token(&mut self) {
         self.cur_tok = self.next_tok.clone();
-        self.next_tok = self.l.next_token();
+        self.next_tok = match self.toks.get(self.pos) {
+            Some(tok) => {
+                self.pos += 1;
+                tok.clone()
+            }
+            None => Token {
+                kind: EOF,
+                loc: self.cur_tok.loc.clone(),
+            },
+        };
     }
 
     // 命令列のパース
+    // エラーにはエラーが起きた token の位置をつける
     pub fn parse(&mut self) -> Result<Asm, String> {
+        // 空行と定数の定義は命令を生成しないので読み飛ばす
+        loop {
+            let r = match &self.cur_tok.kind {
+                NewLine => {
+                    self.next_token();
+                    Ok(())
+                }
+                EQU => self.parse_equ(),
+                SET => self.parse_set(),
+                Symbol(_) if self.next_tok.kind == Equal => self.parse_assign(),
+                _ => break,
+            };
+            r.map_err(|e| format!("{}: {}", self.cur_tok.loc, e))?;
+        }
+
+        let loc = self.cur_tok.loc.clone();
+        let mut asm = self
+            .parse_statement()
+            .map_err(|e| format!("{}: {}", self.cur_tok.loc, e))?;
+        asm.loc = loc;
+        Ok(asm)
+    }
+
+    // 命令またはラベルを一つ parse する
+    fn parse_statement(&mut self) -> Result<Asm, String> {
         match &self.cur_tok.kind {
             Symbol(_) => self.parse_label(),
+            // 1: のような数字ラベル
+            Number(_) if self.next_tok.kind == Colon => self.parse_label(),
             // 命令列の末尾を表す
-            EOF => Ok(Asm {
-                kind: AsmKind::EOASM,
-            }),
+            EOF => Ok(Asm::new(AsmKind::EOASM)),
+            // セクションの切り替え
+            TEXT | DATA | RODATA | BSS => self.parse_section_keyword(),
+            SECTION => self.parse_section(),
+            // データの定義
+            BYTE | HALF | SHORT | WORD | LONG | DWORD | QUAD => self.parse_data(),
+            ASCII | ASCIZ | STRING => self.parse_ascii(),
+            ALIGN | BALIGN | P2ALIGN => self.parse_align(),
+            SPACE | SKIP | ZERO | FILL => self.parse_space(),
+            ORG => self.parse_org(),
+            MEMORY => self.parse_memory(),
+            INCBIN => self.parse_incbin(),
+            // シンボルの binding と大きさ
+            GLOBL | GLOBAL | LOCAL | EXTERN | WEAK => self.parse_bind(),
+            SIZE => self.parse_size(),
             // I 形式の命令
             LW => self.parse_i_lw(),
             ADDI => self.parse_i_addi(),
+            ANDI => self.parse_i_andi(),
+            ORI => self.parse_i_ori(),
+            XORI => self.parse_i_xori(),
+            JALR => self.parse_i_jalr(),
             SLTI => self.parse_i_slti(),
             SLTIU => self.parse_i_sltiu(),
             SLLI => self.parse_i_slli(),
@@ -49,6 +221,9 @@ impl<'a> Parser<'a> {
             SRAI => self.parse_i_srai(),
             // // S形式の命令
             SW => self.parse_s_sw(),
+            // U形式の命令
+            LUI => self.parse_u_lui(),
+            AUIPC => self.parse_u_auipc(),
             // // R形式の命令
             ADD => self.parse_r_add(),
             SUB => self.parse_r_sub(),
@@ -67,7 +242,23 @@ impl<'a> Parser<'a> {
             BGE => self.parse_b_bge(),
             BLTU => self.parse_b_bltu(),
             BGEU => self.parse_b_bgeu(),
-            _ => Err("Parser::parse: unsupported instruction!!".to_string()),
+            // J形式
+            JAL => self.parse_j_jal(),
+            // 疑似命令
+            NOP => self.parse_p_nop(),
+            MV | NOT | NEG | SEQZ | SNEZ | SLTZ | SGTZ => self.parse_p_rd_rs(),
+            BEQZ | BNEZ | BLEZ | BGEZ | BLTZ | BGTZ => self.parse_p_branch_zero(),
+            BGT | BLE | BGTU | BLEU => self.parse_p_branch_swap(),
+            J => self.parse_p_j(),
+            JR => self.parse_p_jr(),
+            RET => self.parse_p_ret(),
+            CALL | TAIL => self.parse_p_call(),
+            LI => self.parse_p_li(),
+            LA => self.parse_p_la(),
+            _ => Err(format!(
+                "Parser::parse: unsupported instruction {:?}!!",
+                self.cur_tok.kind
+            )),
         }
     }
     // cur_tok が kind と一致しているかチェックする
@@ -82,31 +273,242 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
-    // Number(x) トークンかをチェックし、数字を返す
+    // 数字または定数式をチェックし、値を返す
     fn read_number_token(&mut self) -> Result<isize, String> {
+        self.parse_expr()
+    }
+
+    // 定数式を parse して値を返すメソッド
+    pub fn parse_expr(&mut self) -> Result<isize, String> {
+        self.parse_expr_binary(MAX_PRECEDENCE)
+    }
+
+    // 一行の定数式を parse して値を返すメソッド
+    // 式の後に余分な token があればエラーにする
+    pub fn parse_const_expr(&mut self) -> Result<isize, String> {
+        let v = self.parse_expr()?;
+        match self.cur_tok.kind {
+            NewLine | EOF => Ok(v),
+            _ => Err(format!(
+                "Parser::parse_const_expr: unexpected {:?} after expression",
+                self.cur_tok.kind
+            )),
+        }
+    }
+
+    // 優先順位 prec 以下の二項演算子からなる式を parse するメソッド
+    fn parse_expr_binary(&mut self, prec: usize) -> Result<isize, String> {
+        if prec == 0 {
+            return self.parse_expr_unary();
+        }
+
+        let mut lhs = self.parse_expr_binary(prec - 1)?;
+        loop {
+            let op = self.cur_tok.kind.clone();
+            // "A -1" は Symbol, Number(-1) と字句解析されるので、加算として扱う
+            if let Number(n) = op {
+                if n < 0 && prec == precedence(&Plus).unwrap() {
+                    lhs = lhs.wrapping_add(self.parse_expr_binary(prec - 1)?);
+                    continue;
+                }
+            }
+            if precedence(&op) != Some(prec) {
+                return Ok(lhs);
+            }
+            self.next_token();
+            let rhs = self.parse_expr_binary(prec - 1)?;
+            lhs = apply_binary(&op, lhs, rhs)?;
+        }
+    }
+
+    // 単項演算子を parse するメソッド
+    fn parse_expr_unary(&mut self) -> Result<isize, String> {
         match self.cur_tok.kind {
+            Minus => {
+                self.next_token();
+                Ok(self.parse_expr_unary()?.wrapping_neg())
+            }
+            Plus => {
+                self.next_token();
+                self.parse_expr_unary()
+            }
+            Tilde => {
+                self.next_token();
+                Ok(!self.parse_expr_unary()?)
+            }
+            Bang => {
+                self.next_token();
+                Ok((self.parse_expr_unary()? == 0) as isize)
+            }
+            _ => self.parse_expr_primary(),
+        }
+    }
+
+    // 数字、定数、括弧で囲まれた式を parse するメソッド
+    fn parse_expr_primary(&mut self) -> Result<isize, String> {
+        match self.cur_tok.kind.clone() {
             Number(x) => {
                 self.next_token();
                 Ok(x)
             }
+            Symbol(s) => match self.constant(&s) {
+                Some(x) => {
+                    self.next_token();
+                    Ok(x)
+                }
+                None => Err(self.unresolved_symbol(&s)),
+            },
+            LParen => {
+                self.next_token();
+                let v = self.parse_expr()?;
+                self.read_token_kind(RParen)?;
+                Ok(v)
+            }
             _ => Err(format!(
-                "Parser::read_number_token: expected number, but got {:?}",
+                "Parser::parse_expr_primary: expected number, but got {:?}",
                 self.cur_tok.kind
             )),
         }
     }
 
-    fn read_number_or_symbol_token(&mut self) -> Result<(Option<isize>, Option<String>), String> {
-        let asm_kind = match self.cur_tok.kind.clone() {
-            Number(x) => Ok((Some(x), None)),
-            Symbol(s) => Ok((None, Some(s))),
-            _ => Err(format!(
-                "Parser::read_number_or_symbol_token: expected number or symbol, but got {:?}",
-                self.cur_tok.kind
-            )),
+    // レジスタを表すトークンをチェックし、レジスタ番号を返す
+    // 0 ~ 31 の Number(x) または x0 ~ x31 や ABI 名の Symbol(s) を受け付ける
+    fn read_register_token(&mut self) -> Result<isize, String> {
+        let reg = match &self.cur_tok.kind {
+            Number(x) if (0..=31).contains(x) => *x,
+            Number(x) => {
+                return Err(format!(
+                    "Parser::read_register_token: register number {} is out of range (0..=31)",
+                    x
+                ))
+            }
+            Symbol(s) => match lookup_register(s) {
+                Some(x) => x,
+                None => {
+                    return Err(format!(
+                        "Parser::read_register_token: unknown register {:?}",
+                        s
+                    ))
+                }
+            },
+            _ => {
+                return Err(format!(
+                    "Parser::read_register_token: expected register, but got {:?}",
+                    self.cur_tok.kind
+                ))
+            }
+        };
+        self.next_token();
+        Ok(reg)
+    }
+
+    // 即値をチェックし、数字と再配置演算子を返す
+    // 再配置演算子の場合、即値はアセンブル時に解決されるので 0 を返す
+    fn read_imm_token(&mut self) -> Result<(isize, Option<asm::Reloc>), String> {
+        let op = match self.cur_tok.kind.clone() {
+            Reloc(op) => op,
+            _ => {
+                let imm = self.read_number_token()?;
+                return Ok((imm, None));
+            }
+        };
+
+        // %hi(sym) や %hi(sym+4) のような形式
+        self.next_token();
+        self.read_token_kind(LParen)?;
+        let sym = self.read_symbol_token()?;
+        self.next_token();
+        let addend = self.read_addend()?;
+        self.read_token_kind(RParen)?;
+
+        // 定数の場合はここで値を計算する
+        if let Some(v) = self.constant(&sym) {
+            match op.as_str() {
+                "hi" => return Ok((hi20(v.wrapping_add(addend)), None)),
+                "lo" => return Ok((lo12(v.wrapping_add(addend)), None)),
+                _ => {}
+            }
+        }
+
+        let reloc = match op.as_str() {
+            "hi" => asm::Reloc::Hi(sym, addend),
+            "lo" => asm::Reloc::Lo(sym, addend),
+            "pcrel_hi" => asm::Reloc::PcrelHi(sym, addend),
+            // %pcrel_lo は auipc のラベルを指すので、加数は %pcrel_hi の側に書く
+            "pcrel_lo" if addend == 0 => asm::Reloc::PcrelLo(sym),
+            "pcrel_lo" => {
+                return Err(
+                    "Parser::read_imm_token: %pcrel_lo takes the label of auipc without offset"
+                        .to_string(),
+                )
+            }
+            _ => {
+                return Err(format!(
+                    "Parser::read_imm_token: unknown relocation operator %{}",
+                    op
+                ))
+            }
+        };
+        Ok((0, Some(reloc)))
+    }
+
+    // lw, sw のメモリオペランドを読み取り、即値と再配置演算子とベースレジスタを返す
+    // imm(rs1), (rs1) の形式を受け付ける
+    fn read_mem_operand(&mut self) -> Result<(isize, Option<asm::Reloc>, isize), String> {
+        let (imm, reloc) = match self.cur_tok.kind.clone() {
+            // (rs1) はオフセット 0
+            LParen if matches!(&self.next_tok.kind, Symbol(s) if lookup_register(s).is_some()) => {
+                (0, None)
+            }
+            _ => self.read_imm_token()?,
+        };
+
+        // 次のtokenは LParen
+        self.read_token_kind(LParen)?;
+
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
+
+        // 次のtokenは RParen
+        self.read_token_kind(RParen)?;
+
+        Ok((imm, reloc, rs1))
+    }
+
+    // lw rd, label(rs1) と sw rs2, label(rs1), rt のラベルとレジスタを読み取る
+    // 定数とレジスタ以外のシンボルの後に end か (rs1) が続く場合だけラベルとして扱う
+    // (rs1) がなければレジスタは 0 を返す
+    fn read_label_operand(&mut self, end: TokenKind) -> Result<Option<(String, isize)>, String> {
+        let label = match self.cur_tok.kind.clone() {
+            Symbol(s)
+                if (self.next_tok.kind == end || self.next_tok.kind == LParen)
+                    && self.constant(&s).is_none()
+                    && lookup_register(&s).is_none() =>
+            {
+                s
+            }
+            _ => return Ok(None),
         };
         self.next_token();
-        asm_kind
+        if self.cur_tok.kind != LParen {
+            return Ok(Some((label, 0)));
+        }
+        self.next_token();
+        let rs1 = self.read_register_token()?;
+        self.read_token_kind(RParen)?;
+        Ok(Some((label, rs1)))
+    }
+
+    // 分岐先を読み取り、オフセットまたはラベルを返す
+    // 定数でないシンボルはラベルとして扱う
+    fn read_number_or_symbol_token(&mut self) -> Result<(Option<isize>, Option<String>), String> {
+        if let Symbol(s) = self.cur_tok.kind.clone() {
+            if self.constant(&s).is_none() {
+                self.next_token();
+                return Ok((None, Some(s)));
+            }
+        }
+        Ok((Some(self.parse_expr()?), None))
     }
 
     fn read_symbol_token(&mut self) -> Result<String, String> {
@@ -119,106 +521,827 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_label(&mut self) -> Result<Asm, String> {
-        let l = self.read_symbol_token()?;
+    // .equ name, expr を parse するメソッド
+    // .equ で定義した定数は再定義できない
+    fn parse_equ(&mut self) -> Result<(), String> {
+        // 先頭は EQU だとわかっているので、次の token に進める
+        self.next_token();
+        self.parse_constant(false)
+    }
+
+    // .set name, expr を parse するメソッド
+    fn parse_set(&mut self) -> Result<(), String> {
+        // 先頭は SET だとわかっているので、次の token に進める
+        self.next_token();
+        self.parse_constant(true)
+    }
+
+    // .equ, .set に続く name, expr を parse して定数を定義するメソッド
+    fn parse_constant(&mut self, redefinable: bool) -> Result<(), String> {
+        // 次の token は Symbol(s)
+        let name = self.read_symbol_token()?;
+        self.next_token();
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token は定数式
+        let v = self.parse_expr()?;
+
+        // 最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        self.constants.define(&name, v, redefinable)
+    }
+
+    // name = expr を parse するメソッド
+    // = は .set と同じく再定義できる
+    fn parse_assign(&mut self) -> Result<(), String> {
+        let name = self.read_symbol_token()?;
+        self.next_token();
+
+        // 次の token は Equal
+        self.read_token_kind(Equal)?;
+
+        // 次の token は定数式
+        let v = self.parse_expr()?;
+
+        // 最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        self.constants.define(&name, v, true)
+    }
+
+    fn parse_label(&mut self) -> Result<Asm, String> {
+        let l = match self.cur_tok.kind {
+            Number(n) if n >= 0 => n.to_string(),
+            _ => self.read_symbol_token()?,
+        };
+
+        self.next_token();
+
+        self.read_token_kind(Colon)?;
+
+        Ok(Asm::new(AsmKind::LABEL { l }))
+    }
+
+    // .text, .data, .rodata, .bss を parse するメソッド
+    fn parse_section_keyword(&mut self) -> Result<Asm, String> {
+        let name = self.cur_tok.kind.to_string();
+        self.next_token();
+        self.read_token_kind(NewLine)?;
+        Ok(Asm::new(AsmKind::SECTION {
+            name: asm::output_section(&name),
+        }))
+    }
+
+    // .section name[, flags...] を parse するメソッド
+    // name は symbol, 文字列, セクションのディレクティブのいずれか
+    // フラグやセクションの型は使わないので読み飛ばす
+    fn parse_section(&mut self) -> Result<Asm, String> {
+        // 先頭は SECTION だとわかっているので、次の token に進める
+        self.next_token();
+
+        // .note.GNU-stack のように - を含む名前は複数の token に分かれるのでつなげる
+        let mut name = String::new();
+        while !matches!(self.cur_tok.kind, Comma | NewLine | EOF) {
+            match &self.cur_tok.kind {
+                Str(s) => name.push_str(&String::from_utf8_lossy(s)),
+                kind => name.push_str(&kind.to_string()),
+            }
+            self.next_token();
+        }
+        if name.is_empty() {
+            return Err(format!(
+                "Parser::parse_section: expected section name, but got {:?}",
+                self.cur_tok.kind
+            ));
+        }
+
+        while !matches!(self.cur_tok.kind, NewLine | EOF) {
+            self.next_token();
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::SECTION {
+            name: asm::output_section(&name),
+        }))
+    }
+
+    // .byte, .half, .word, .dword などを parse するメソッド
+    // 値は定数式またはラベル (ラベル +- 定数式) をカンマで区切って並べる
+    fn parse_data(&mut self) -> Result<Asm, String> {
+        let width = match self.cur_tok.kind {
+            BYTE => 1,
+            HALF | SHORT => 2,
+            WORD | LONG => 4,
+            _ => 8,
+        };
+        self.next_token();
+
+        let mut items = vec![];
+        loop {
+            items.push(self.read_data_item()?);
+            if self.cur_tok.kind != Comma {
+                break;
+            }
+            self.next_token();
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::DATA { width, items }))
+    }
+
+    // .ascii, .asciz, .string を parse するメソッド
+    // 文字列はカンマで区切って並べられ、.asciz, .string はそれぞれの末尾に NUL を置く
+    fn parse_ascii(&mut self) -> Result<Asm, String> {
+        let nul = self.cur_tok.kind != ASCII;
+        self.next_token();
+
+        let mut bytes = vec![];
+        loop {
+            match &self.cur_tok.kind {
+                Str(s) => bytes.extend_from_slice(s),
+                _ => {
+                    return Err(format!(
+                        "Parser::parse_ascii: expected string, but got {:?}",
+                        self.cur_tok.kind
+                    ))
+                }
+            }
+            if nul {
+                bytes.push(0);
+            }
+            self.next_token();
+            if self.cur_tok.kind != Comma {
+                break;
+            }
+            self.next_token();
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::ASCII { bytes }))
+    }
+
+    // .align, .balign, .p2align を parse するメソッド
+    // .align n, .p2align n は 2^n バイト、.balign n は n バイトに揃える
+    // 続く fill と max は省略でき、.p2align 4,,8 のように fill だけ省略してもよい
+    fn parse_align(&mut self) -> Result<Asm, String> {
+        let pow2 = self.cur_tok.kind != BALIGN;
+        self.next_token();
+
+        let n = self.parse_expr()?;
+        let align = match n {
+            0..=30 if pow2 => 1 << n,
+            _ if pow2 => {
+                return Err(format!(
+                    "Parser::parse_align: alignment 2^{} is out of range",
+                    n
+                ))
+            }
+            _ => n,
+        };
+        if align <= 0 || align & (align - 1) != 0 {
+            return Err(format!(
+                "Parser::parse_align: alignment {} is not a power of 2",
+                align
+            ));
+        }
+
+        let mut fill = None;
+        let mut max = None;
+        if self.cur_tok.kind == Comma {
+            self.next_token();
+            if !matches!(self.cur_tok.kind, Comma | NewLine) {
+                fill = Some(self.parse_expr()?);
+            }
+            if self.cur_tok.kind == Comma {
+                self.next_token();
+                max = Some(self.parse_expr()?);
+            }
+        }
+
+        // 引数は改行を読む前に確かめて、エラーの位置をこの行にする
+        // fill は 1 バイトの値
+        if let Some(v) = fill.filter(|v| !(-128..256).contains(v)) {
+            return Err(format!(
+                "Parser::parse_align: fill value {} does not fit in a byte",
+                v
+            ));
+        }
+        if let Some(v) = max.filter(|v| *v < 0) {
+            return Err(format!("Parser::parse_align: max skip {} is negative", v));
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::ALIGN { align, fill, max }))
+    }
+
+    // .space, .skip, .zero, .fill を parse するメソッド
+    // .space size[, fill] と .skip size[, fill] は fill (1 バイト) を size 個並べる
+    // .zero size は 0 を size 個、.fill repeat[, size[, value]] は size バイトの value を repeat 個並べる
+    fn parse_space(&mut self) -> Result<Asm, String> {
+        let op = self.cur_tok.kind.clone();
+        self.next_token();
+
+        let repeat = self.parse_expr()?;
+        let mut args = vec![];
+        while self.cur_tok.kind == Comma {
+            self.next_token();
+            args.push(self.parse_expr()?);
+        }
+
+        // 引数は改行を読む前に確かめて、エラーの位置をこの行にする
+        let (size, value) = match (&op, args.as_slice()) {
+            (ZERO, []) | (SPACE | SKIP | FILL, []) => (1, 0),
+            (SPACE | SKIP, [v]) => (1, *v),
+            (FILL, [size]) => (*size, 0),
+            (FILL, [size, v]) => (*size, *v),
+            _ => {
+                return Err(format!(
+                    "Parser::parse_space: too many arguments for {}",
+                    op
+                ))
+            }
+        };
+        if repeat < 0 {
+            return Err(format!("Parser::parse_space: size {} is negative", repeat));
+        }
+        if !(0..=8).contains(&size) {
+            return Err(format!(
+                "Parser::parse_space: fill size {} is not in 0..=8",
+                size
+            ));
+        }
+        // .space の fill は 1 バイトの値
+        if size == 1 && !(-128..256).contains(&value) {
+            return Err(format!(
+                "Parser::parse_space: fill value {} does not fit in a byte",
+                value
+            ));
+        }
+        match repeat.checked_mul(size) {
+            Some(total) if total <= asm::ADDRESS_SPACE => {}
+            _ => {
+                return Err(format!(
+                    "Parser::parse_space: {} * {} bytes do not fit in the 32-bit address space",
+                    repeat, size
+                ))
+            }
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::SPACE {
+            repeat,
+            size,
+            value,
+        }))
+    }
+
+    // .org addr[, fill] を parse するメソッド
+    // addr はセクションの先頭からの位置で、現在のセクションの位置をそこまで進める
+    fn parse_org(&mut self) -> Result<Asm, String> {
+        // 先頭は ORG だとわかっているので、次の token に進める
+        self.next_token();
+
+        let addr = self.parse_expr()?;
+        let fill = match self.cur_tok.kind {
+            Comma => {
+                self.next_token();
+                Some(self.parse_expr()?)
+            }
+            _ => None,
+        };
+
+        // 引数は改行を読む前に確かめて、エラーの位置をこの行にする
+        if !(0..asm::ADDRESS_SPACE).contains(&addr) {
+            return Err(format!(
+                "Parser::parse_org: address {:#x} is out of the 32-bit address space",
+                addr
+            ));
+        }
+        if let Some(v) = fill.filter(|v| !(-128..256).contains(v)) {
+            return Err(format!(
+                "Parser::parse_org: fill value {} does not fit in a byte",
+                v
+            ));
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::ORG { addr, fill }))
+    }
+
+    // .memory "ROM: 0x0000, 4K; RAM: 0x1000, 2K" を parse するメソッド
+    fn parse_memory(&mut self) -> Result<Asm, String> {
+        // 先頭は MEMORY だとわかっているので、次の token に進める
+        self.next_token();
+
+        let map = match &self.cur_tok.kind {
+            Str(s) => MemoryMap::parse(&String::from_utf8_lossy(s))?,
+            _ => {
+                return Err(format!(
+                    "Parser::parse_memory: expected string, but got {:?}",
+                    self.cur_tok.kind
+                ))
+            }
+        };
+        self.next_token();
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::MEMORY { map }))
+    }
+
+    // .incbin "file", offset, length を parse するメソッド
+    // ファイルの offset バイト目から length バイトを埋め込む
+    // offset, length は省略でき、省略した場合はファイルの先頭から末尾まで
+    // ファイル名は Preprocessor で .include と同じように探したパスになっている
+    fn parse_incbin(&mut self) -> Result<Asm, String> {
+        // 先頭は INCBIN だとわかっているので、次の token に進める
+        self.next_token();
+
+        let path = match &self.cur_tok.kind {
+            Str(s) => String::from_utf8_lossy(s).into_owned(),
+            _ => {
+                return Err(format!(
+                    "Parser::parse_incbin: expected file name string, but got {:?}",
+                    self.cur_tok.kind
+                ))
+            }
+        };
+        // ファイルを読めないエラーはファイル名の位置につける
+        let data =
+            std::fs::read(&path).map_err(|e| format!("Parser::parse_incbin: {}: {}", path, e))?;
+        self.next_token();
+
+        let offset = match self.cur_tok.kind {
+            Comma => {
+                self.next_token();
+                self.parse_expr()?
+            }
+            _ => 0,
+        };
+        let length = match self.cur_tok.kind {
+            Comma => {
+                self.next_token();
+                Some(self.parse_expr()?)
+            }
+            _ => None,
+        };
+
+        // 範囲のエラーは .incbin の行につける
+        let size = data.len() as isize;
+        if !(0..=size).contains(&offset) {
+            return Err(format!(
+                "Parser::parse_incbin: offset {} is out of range for {} ({} bytes)",
+                offset, path, size
+            ));
+        }
+        // offset + length は大きな length で溢れることがあるので checked_add で計算する
+        let end = match length {
+            Some(length) => match offset.checked_add(length) {
+                Some(end) if length >= 0 && end <= size => end,
+                _ => {
+                    return Err(format!(
+                        "Parser::parse_incbin: length {} at offset {} is out of range for {} ({} bytes)",
+                        length, offset, path, size
+                    ))
+                }
+            },
+            None => size,
+        };
+        let bytes = data[offset as usize..end as usize].to_vec();
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::ASCII { bytes }))
+    }
+
+    // .globl, .global, .local, .extern, .weak を parse するメソッド
+    // シンボルはカンマで区切って並べられる
+    fn parse_bind(&mut self) -> Result<Asm, String> {
+        let binding = match self.cur_tok.kind {
+            LOCAL => Binding::Local,
+            WEAK => Binding::Weak,
+            _ => Binding::Global,
+        };
+        self.next_token();
+
+        let mut names = vec![self.read_symbol_token()?];
+        self.next_token();
+        while self.cur_tok.kind == Comma {
+            self.next_token();
+            names.push(self.read_symbol_token()?);
+            self.next_token();
+        }
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::BIND { binding, names }))
+    }
+
+    // .size name, size を parse するメソッド
+    // size は定数式のほかに、. - name や .Lend - name のようなアドレスの差を書ける
+    fn parse_size(&mut self) -> Result<Asm, String> {
+        // 先頭は SIZE だとわかっているので、次の token に進める
+        self.next_token();
+
+        let name = self.read_symbol_token()?;
+        self.next_token();
+        self.read_token_kind(Comma)?;
+
+        let end = match &self.cur_tok.kind {
+            Dot => Some(None),
+            Symbol(s) if self.next_tok.kind == Minus && self.constant(s).is_none() => {
+                Some(Some(s.clone()))
+            }
+            _ => None,
+        };
+        let size = match end {
+            Some(end) => {
+                self.next_token();
+                self.read_token_kind(Minus)?;
+                let start = self.read_symbol_token()?;
+                self.next_token();
+                asm::SymbolSize::Diff { end, start }
+            }
+            None => asm::SymbolSize::Const(self.parse_expr()?),
+        };
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::SIZE { name, size }))
+    }
+
+    // .word などの一つの値を読み取る
+    // 定数でないシンボルはラベルとして扱い、アドレスはアセンブル時に決める
+    // ラベルを含む値は、ラベルと定数式を + と - でつないだ式で、
+    // 足すラベルと引くラベルを一つずつまで書ける (4 + label, label - 4, .Lx - .Ly)
+    fn read_data_item(&mut self) -> Result<asm::DataItem, String> {
+        if !self.data_item_has_label() {
+            return Ok(asm::DataItem {
+                value: self.parse_expr()?,
+                label: None,
+                sub: None,
+            });
+        }
+
+        let mut item = asm::DataItem {
+            value: 0,
+            label: None,
+            sub: None,
+        };
+        let mut neg = false;
+        if self.cur_tok.kind == Minus && self.is_data_label(&self.next_tok.kind) {
+            neg = true;
+            self.next_token();
+        }
+        loop {
+            if self.is_data_label(&self.cur_tok.kind) {
+                let s = self.read_symbol_token()?;
+                self.next_token();
+                let slot = if neg { &mut item.sub } else { &mut item.label };
+                if let Some(prev) = slot {
+                    return Err(format!(
+                        "Parser::read_data_item: cannot combine labels {:?} and {:?}",
+                        prev, s
+                    ));
+                }
+                *slot = Some(s);
+            } else {
+                let v = self.parse_expr_binary(precedence(&Plus).unwrap() - 1)?;
+                item.value = item
+                    .value
+                    .wrapping_add(if neg { v.wrapping_neg() } else { v });
+            }
+            // "label -4" は Symbol, Number(-4) と字句解析されるので、加算として扱う
+            match self.cur_tok.kind {
+                Plus => neg = false,
+                Minus => neg = true,
+                Number(n) if n < 0 => {
+                    neg = false;
+                    continue;
+                }
+                _ => break,
+            }
+            self.next_token();
+        }
+        if let (None, Some(sub)) = (&item.label, &item.sub) {
+            return Err(format!(
+                "Parser::read_data_item: label {:?} cannot be negated",
+                sub
+            ));
+        }
+        Ok(item)
+    }
+
+    // .word などの一つの値がラベルを含むかを判定する
+    // 値の終わり (括弧の外のカンマか行末) までの token を先読みする
+    fn data_item_has_label(&self) -> bool {
+        let mut depth = 0;
+        let rest = self.toks.get(self.pos..).unwrap_or_default();
+        for kind in [&self.cur_tok.kind, &self.next_tok.kind]
+            .iter()
+            .copied()
+            .chain(rest.iter().map(|tok| &tok.kind))
+        {
+            match kind {
+                LParen => depth += 1,
+                RParen => depth -= 1,
+                Comma if depth == 0 => return false,
+                NewLine | EOF => return false,
+                kind if self.is_data_label(kind) => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    // 定数でないシンボルかを判定する
+    fn is_data_label(&self, kind: &TokenKind) -> bool {
+        matches!(kind, Symbol(s) if self.constant(s).is_none())
+    }
+
+    // ラベルの後の label + 4, label - 4 のようなオフセットを読み取るメソッド
+    // "label -4" は Symbol, Number(-4) と字句解析される
+    // オフセットがなければ 0 を返す
+    fn read_addend(&mut self) -> Result<isize, String> {
+        match self.cur_tok.kind {
+            Plus | Minus => self.parse_expr(),
+            Number(n) if n < 0 => self.parse_expr(),
+            _ => Ok(0),
+        }
+    }
+
+    // lw 命令をparseするメソッド
+    fn parse_i_lw(&mut self) -> Result<Asm, String> {
+        // 先頭はLWだとわかっているので、つぎのTokenに進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
+
+        // 次のtokenは Comma
+        self.read_token_kind(Comma)?;
+
+        // lw rd, label と lw rd, label(rs1) は auipc を使った PC 相対のロードに展開する
+        // rd にアドレスを作ってから rs1 を足すので、rd と rs1 は別のレジスタにする
+        // エラーの位置をこの行にするため、改行を読む前に確かめる
+        if let Some((label, rs1)) = self.read_label_operand(NewLine)? {
+            if rs1 != 0 && rs1 == rd {
+                return Err(format!(
+                    "Parser::parse_i_lw: lw rd, {}(rs1) needs rd different from rs1",
+                    label
+                ));
+            }
+            self.read_token_kind(NewLine)?;
+            return Ok(Asm::new(AsmKind::LWSYM { rd, rs1, label }));
+        }
+
+        // 次の token は imm(rs1), (rs1)
+        let (imm, reloc, rs1) = self.read_mem_operand()?;
+
+        // 命令列の末端は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::with_reloc(AsmKind::LW { imm, rs1, rd }, reloc))
+    }
+
+    // sw 命令を parse するメソッド
+    fn parse_s_sw(&mut self) -> Result<Asm, String> {
+        // 先頭はSWだとわかっているので、つぎのTokenに進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
+
+        // 次のtokenは Comma
+        self.read_token_kind(Comma)?;
+
+        // sw rs2, label, rt と sw rs2, label(rs1), rt は rt を使った PC 相対のストアに展開する
+        // rt にアドレスを作ってから rs1 を足して rs2 をストアするので、
+        // rt は rs1 とも rs2 とも別のレジスタにする
+        // エラーの位置をこの行にするため、改行を読む前に確かめる
+        if let Some((label, rs1)) = self.read_label_operand(Comma)? {
+            self.read_token_kind(Comma)?;
+            let rt = self.read_register_token()?;
+            if rs1 != 0 && rs1 == rt {
+                return Err(format!(
+                    "Parser::parse_s_sw: sw rs2, {}(rs1), rt needs rt different from rs1",
+                    label
+                ));
+            }
+            if rs2 == rt {
+                return Err(format!(
+                    "Parser::parse_s_sw: sw rs2, {}, rt needs rt different from rs2",
+                    label
+                ));
+            }
+            self.read_token_kind(NewLine)?;
+            return Ok(Asm::new(AsmKind::SWSYM {
+                rs2,
+                rs1,
+                rt,
+                label,
+            }));
+        }
+
+        // 次の token は imm(rs1), (rs1)
+        let (imm, reloc, rs1) = self.read_mem_operand()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::with_reloc(AsmKind::SW { imm, rs2, rs1 }, reloc))
+    }
+
+    // lui 命令を parse するメソッド
+    fn parse_u_lui(&mut self) -> Result<Asm, String> {
+        // 先頭は LUI だとわかっているので、次の token に進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token は Number(x) または %hi(sym)
+        let (imm, reloc) = self.read_imm_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::with_reloc(AsmKind::LUI { imm, rd }, reloc))
+    }
+
+    // auipc 命令を parse するメソッド
+    fn parse_u_auipc(&mut self) -> Result<Asm, String> {
+        // 先頭は AUIPC だとわかっているので、次の token に進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token は Number(x) または %pcrel_hi(sym)
+        let (imm, reloc) = self.read_imm_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::with_reloc(AsmKind::AUIPC { imm, rd }, reloc))
+    }
+
+    // addi 命令を parse するメソッド
+    fn parse_i_addi(&mut self) -> Result<Asm, String> {
+        // 先頭は ADDI だとわかっているので、次の token に進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
-        self.next_token();
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
 
-        self.read_token_kind(Colon)?;
+        // 次の token は Number(x)
+        let (imm, reloc) = self.read_imm_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::LABEL { l },
-        })
+        Ok(Asm::with_reloc(AsmKind::ADDI { imm, rs1, rd }, reloc))
     }
 
-    // lw 命令をparseするメソッド
-    fn parse_i_lw(&mut self) -> Result<Asm, String> {
-        // 先頭はLWだとわかっているので、つぎのTokenに進める
+    // andi 命令を parse するメソッド
+    fn parse_i_andi(&mut self) -> Result<Asm, String> {
+        // 先頭は ANDI だとわかっているので、次の token に進める
         self.next_token();
 
-        //　次のtokenはレジスタ番号を表す数字 "rd"
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
-        // 次のtokenは Comma
+        // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次のtokenは Number(x)
-        let imm = self.read_number_token()?;
-
-        // 次のtokenは LParen
-        self.read_token_kind(LParen)?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
-        //　次のtokenは Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
 
-        // 次のtokenは RParen
-        self.read_token_kind(RParen)?;
+        // 次の token は Number(x)
+        let (imm, reloc) = self.read_imm_token()?;
 
-        // 命令列の末端は改行文字
+        // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::LW { imm, rs1, rd },
-        })
+        Ok(Asm::with_reloc(AsmKind::ANDI { imm, rs1, rd }, reloc))
     }
 
-    // sw 命令を parse するメソッド
-    fn parse_s_sw(&mut self) -> Result<Asm, String> {
-        // 先頭はSWだとわかっているので、つぎのTokenに進める
+    // ori 命令を parse するメソッド
+    fn parse_i_ori(&mut self) -> Result<Asm, String> {
+        // 先頭は ORI だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次のtokenは Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
-        // 次のtokenは Comma
+        // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次のtokenは Number(x)
-        let imm = self.read_number_token()?;
-
-        // 次のtokenは LParen
-        self.read_token_kind(LParen)?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
-        // 次のtokenは Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
 
-        // 次のtokenは RParen
-        self.read_token_kind(RParen)?;
+        // 次の token は Number(x)
+        let (imm, reloc) = self.read_imm_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SW { imm, rs2, rs1 },
-        })
+        Ok(Asm::with_reloc(AsmKind::ORI { imm, rs1, rd }, reloc))
     }
 
-    // addi 命令を parse するメソッド
-    fn parse_i_addi(&mut self) -> Result<Asm, String> {
-        // 先頭は ADDI だとわかっているので、次の token に進める
+    // xori 命令を parse するメソッド
+    fn parse_i_xori(&mut self) -> Result<Asm, String> {
+        // 先頭は XORI だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token はNumber(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
         // 次の token は Number(x)
-        let imm = self.read_number_token()?;
+        let (imm, reloc) = self.read_imm_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::with_reloc(AsmKind::XORI { imm, rs1, rd }, reloc))
+    }
+
+    // jalr 命令を parse するメソッド
+    // jalr rs1, jalr rd, rs1, jalr rd, rs1, imm, jalr rd, imm(rs1) の形式を受け付ける
+    fn parse_i_jalr(&mut self) -> Result<Asm, String> {
+        // 先頭は JALR だとわかっているので、次の token に進める
+        self.next_token();
+
+        // rd を省略した場合は ra に戻りアドレスを保存する
+        let first = self.read_register_token()?;
+        if self.cur_tok.kind == NewLine {
+            self.next_token();
+            return Ok(Asm::new(AsmKind::JALR {
+                imm: 0,
+                rs1: first,
+                rd: 1,
+            }));
+        }
+        let rd = first;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        let (imm, reloc, rs1) = match &self.cur_tok.kind {
+            // レジスタ名の後に即値を続ける形式
+            Symbol(s) if lookup_register(s).is_some() => {
+                let rs1 = self.read_register_token()?;
+                if self.cur_tok.kind == Comma {
+                    self.next_token();
+                    let (imm, reloc) = self.read_imm_token()?;
+                    (imm, reloc, rs1)
+                } else {
+                    (0, None, rs1)
+                }
+            }
+            // imm(rs1) の形式
+            _ => {
+                let (imm, reloc) = self.read_imm_token()?;
+                self.read_token_kind(LParen)?;
+                let rs1 = self.read_register_token()?;
+                self.read_token_kind(RParen)?;
+                (imm, reloc, rs1)
+            }
+        };
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::ADDI { imm, rs1, rd },
-        })
+        Ok(Asm::with_reloc(AsmKind::JALR { imm, rs1, rd }, reloc))
     }
 
     // add 命令を parse するメソッド
@@ -226,338 +1349,314 @@ impl<'a> Parser<'a> {
         // 先頭は ADD だとわかっているので、次の token をに進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::ADD { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::ADD { rs2, rs1, rd }))
     }
 
     fn parse_r_sub(&mut self) -> Result<Asm, String> {
         // 先頭は SUB だとわかっているので、次の token をに進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SUB { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SUB { rs2, rs1, rd }))
     }
 
     fn parse_r_and(&mut self) -> Result<Asm, String> {
         // 先頭は AND だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::AND { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::AND { rs2, rs1, rd }))
     }
 
     fn parse_r_or(&mut self) -> Result<Asm, String> {
         // 先頭は AND だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::OR { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::OR { rs2, rs1, rd }))
     }
 
     fn parse_r_xor(&mut self) -> Result<Asm, String> {
         // 先頭は AND だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::XOR { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::XOR { rs2, rs1, rd }))
     }
 
     fn parse_r_slt(&mut self) -> Result<Asm, String> {
         // 先頭は SLT だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLT { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SLT { rs2, rs1, rd }))
     }
 
     fn parse_r_sltu(&mut self) -> Result<Asm, String> {
         // 先頭は SLTU だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLTU { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SLTU { rs2, rs1, rd }))
     }
 
     fn parse_i_slti(&mut self) -> Result<Asm, String> {
         // 先頭は SLTI だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
         // 次の token は Number(x)
-        let imm = self.read_number_token()?;
+        let (imm, reloc) = self.read_imm_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLTI { imm, rs1, rd },
-        })
+        Ok(Asm::with_reloc(AsmKind::SLTI { imm, rs1, rd }, reloc))
     }
 
     fn parse_i_sltiu(&mut self) -> Result<Asm, String> {
         // 先頭は SLTIU だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
         // 次の token は Number(x)
-        let imm = self.read_number_token()?;
+        let (imm, reloc) = self.read_imm_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLTIU { imm, rs1, rd },
-        })
+        Ok(Asm::with_reloc(AsmKind::SLTIU { imm, rs1, rd }, reloc))
     }
 
     fn parse_r_sll(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLL { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SLL { rs2, rs1, rd }))
     }
 
     fn parse_r_srl(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SRL { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SRL { rs2, rs1, rd }))
     }
 
     fn parse_r_sra(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SRA { rs2, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SRA { rs2, rs1, rd }))
     }
 
     fn parse_i_slli(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -568,23 +1667,21 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SLLI { imm, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SLLI { imm, rs1, rd }))
     }
 
     fn parse_i_srli(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -595,23 +1692,21 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SRLI { imm, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SRLI { imm, rs1, rd }))
     }
 
     fn parse_i_srai(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rd = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rd = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -622,23 +1717,21 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::SRAI { imm, rs1, rd },
-        })
+        Ok(Asm::new(AsmKind::SRAI { imm, rs1, rd }))
     }
 
     fn parse_b_beq(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -649,28 +1742,26 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BEQ {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        Ok(Asm::new(AsmKind::BEQ {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
     }
 
     fn parse_b_bne(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -681,28 +1772,26 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BNE {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        Ok(Asm::new(AsmKind::BNE {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
     }
 
     fn parse_b_blt(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -713,28 +1802,26 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BLT {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        Ok(Asm::new(AsmKind::BLT {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
     }
 
     fn parse_b_bge(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -745,28 +1832,56 @@ impl<'a> Parser<'a> {
         // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BGE {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        Ok(Asm::new(AsmKind::BGE {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
     }
 
     fn parse_b_bltu(&mut self) -> Result<Asm, String> {
         // 先頭は SLL だとわかっているので、次の token に進める
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token は Number(x)またはSymbol(s)
+        let (imm, label) = self.read_number_or_symbol_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::BLTU {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
+    }
+
+    fn parse_b_bgeu(&mut self) -> Result<Asm, String> {
+        // 先頭は SLL だとわかっているので、次の token に進める
+        self.next_token();
+
+        // 次の token はレジスタ
+        let rs1 = self.read_register_token()?;
+
+        // 次の token は Comma
+        self.read_token_kind(Comma)?;
+
+        // 次の token はレジスタ
+        let rs2 = self.read_register_token()?;
 
         // 次の token は Comma
         self.read_token_kind(Comma)?;
@@ -774,58 +1889,357 @@ impl<'a> Parser<'a> {
         // 次の token は Number(x)またはSymbol(s)
         let (imm, label) = self.read_number_or_symbol_token()?;
 
-        // 命令列の最後は改行文字
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::BGEU {
+            imm,
+            rs2,
+            rs1,
+            label,
+        }))
+    }
+
+    // jal 命令を parse するメソッド
+    // rd を省略した場合は ra に戻りアドレスを保存する
+    fn parse_j_jal(&mut self) -> Result<Asm, String> {
+        // 先頭は JAL だとわかっているので、次の token に進める
+        self.next_token();
+
+        // jal rd, target の形式
+        let rd = if self.next_tok.kind == Comma {
+            let rd = self.read_register_token()?;
+            self.read_token_kind(Comma)?;
+            rd
+        } else {
+            1
+        };
+
+        // 次の token は Number(x)またはSymbol(s)
+        let (imm, label) = self.read_number_or_symbol_token()?;
+
+        // 命令列の最後は改行文字
+        self.read_token_kind(NewLine)?;
+
+        Ok(Asm::new(AsmKind::JAL { imm, rd, label }))
+    }
+
+    // nop 疑似命令を parse するメソッド
+    // addi x0, x0, 0 に展開する
+    fn parse_p_nop(&mut self) -> Result<Asm, String> {
+        self.next_token();
+        self.read_token_kind(NewLine)?;
+        Ok(Asm::new(AsmKind::ADDI {
+            imm: 0,
+            rs1: 0,
+            rd: 0,
+        }))
+    }
+
+    // rd, rs を取る疑似命令を parse するメソッド
+    fn parse_p_rd_rs(&mut self) -> Result<Asm, String> {
+        let op = self.cur_tok.kind.clone();
+        self.next_token();
+
+        let rd = self.read_register_token()?;
+        self.read_token_kind(Comma)?;
+        let rs = self.read_register_token()?;
+        self.read_token_kind(NewLine)?;
+
+        let kind = match op {
+            // addi rd, rs, 0
+            MV => AsmKind::ADDI {
+                imm: 0,
+                rs1: rs,
+                rd,
+            },
+            // xori rd, rs, -1
+            NOT => AsmKind::XORI {
+                imm: -1,
+                rs1: rs,
+                rd,
+            },
+            // sub rd, x0, rs
+            NEG => AsmKind::SUB {
+                rs2: rs,
+                rs1: 0,
+                rd,
+            },
+            // sltiu rd, rs, 1
+            SEQZ => AsmKind::SLTIU {
+                imm: 1,
+                rs1: rs,
+                rd,
+            },
+            // sltu rd, x0, rs
+            SNEZ => AsmKind::SLTU {
+                rs2: rs,
+                rs1: 0,
+                rd,
+            },
+            // slt rd, rs, x0
+            SLTZ => AsmKind::SLT {
+                rs2: 0,
+                rs1: rs,
+                rd,
+            },
+            // slt rd, x0, rs
+            _ => AsmKind::SLT {
+                rs2: rs,
+                rs1: 0,
+                rd,
+            },
+        };
+        Ok(Asm::new(kind))
+    }
+
+    // beqz rs, target のように 0 と比較する分岐の疑似命令を parse するメソッド
+    fn parse_p_branch_zero(&mut self) -> Result<Asm, String> {
+        let op = self.cur_tok.kind.clone();
+        self.next_token();
+
+        let rs = self.read_register_token()?;
+        self.read_token_kind(Comma)?;
+        let (imm, label) = self.read_number_or_symbol_token()?;
+        self.read_token_kind(NewLine)?;
+
+        let kind = match op {
+            // beq rs, x0, target
+            BEQZ => AsmKind::BEQ {
+                imm,
+                rs2: 0,
+                rs1: rs,
+                label,
+            },
+            // bne rs, x0, target
+            BNEZ => AsmKind::BNE {
+                imm,
+                rs2: 0,
+                rs1: rs,
+                label,
+            },
+            // bge x0, rs, target
+            BLEZ => AsmKind::BGE {
+                imm,
+                rs2: rs,
+                rs1: 0,
+                label,
+            },
+            // bge rs, x0, target
+            BGEZ => AsmKind::BGE {
+                imm,
+                rs2: 0,
+                rs1: rs,
+                label,
+            },
+            // blt rs, x0, target
+            BLTZ => AsmKind::BLT {
+                imm,
+                rs2: 0,
+                rs1: rs,
+                label,
+            },
+            // blt x0, rs, target
+            _ => AsmKind::BLT {
+                imm,
+                rs2: rs,
+                rs1: 0,
+                label,
+            },
+        };
+        Ok(Asm::new(kind))
+    }
+
+    // bgt rs, rt, target のようにオペランドを入れ替える分岐の疑似命令を parse するメソッド
+    fn parse_p_branch_swap(&mut self) -> Result<Asm, String> {
+        let op = self.cur_tok.kind.clone();
+        self.next_token();
+
+        let rs = self.read_register_token()?;
+        self.read_token_kind(Comma)?;
+        let rt = self.read_register_token()?;
+        self.read_token_kind(Comma)?;
+        let (imm, label) = self.read_number_or_symbol_token()?;
+        self.read_token_kind(NewLine)?;
+
+        let kind = match op {
+            // blt rt, rs, target
+            BGT => AsmKind::BLT {
+                imm,
+                rs2: rs,
+                rs1: rt,
+                label,
+            },
+            // bge rt, rs, target
+            BLE => AsmKind::BGE {
+                imm,
+                rs2: rs,
+                rs1: rt,
+                label,
+            },
+            // bltu rt, rs, target
+            BGTU => AsmKind::BLTU {
+                imm,
+                rs2: rs,
+                rs1: rt,
+                label,
+            },
+            // bgeu rt, rs, target
+            _ => AsmKind::BGEU {
+                imm,
+                rs2: rs,
+                rs1: rt,
+                label,
+            },
+        };
+        Ok(Asm::new(kind))
+    }
+
+    // j target 疑似命令を parse するメソッド
+    // jal x0, target に展開する
+    fn parse_p_j(&mut self) -> Result<Asm, String> {
+        self.next_token();
+        let (imm, label) = self.read_number_or_symbol_token()?;
+        self.read_token_kind(NewLine)?;
+        Ok(Asm::new(AsmKind::JAL { imm, rd: 0, label }))
+    }
+
+    // jr rs 疑似命令を parse するメソッド
+    // jalr x0, 0(rs) に展開する
+    fn parse_p_jr(&mut self) -> Result<Asm, String> {
+        self.next_token();
+        let rs1 = self.read_register_token()?;
+        self.read_token_kind(NewLine)?;
+        Ok(Asm::new(AsmKind::JALR { imm: 0, rs1, rd: 0 }))
+    }
+
+    // ret 疑似命令を parse するメソッド
+    // jalr x0, 0(ra) に展開する
+    fn parse_p_ret(&mut self) -> Result<Asm, String> {
+        self.next_token();
+        self.read_token_kind(NewLine)?;
+        Ok(Asm::new(AsmKind::JALR {
+            imm: 0,
+            rs1: 1,
+            rd: 0,
+        }))
+    }
+
+    // call label, tail label 疑似命令を parse するメソッド
+    // auipc と jalr の二命令に展開する
+    fn parse_p_call(&mut self) -> Result<Asm, String> {
+        let op = self.cur_tok.kind.clone();
+        self.next_token();
+
+        let label = self.read_symbol_token()?;
+        self.next_token();
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BLTU {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        match op {
+            CALL => Ok(Asm::new(AsmKind::CALL { label })),
+            _ => Ok(Asm::new(AsmKind::TAIL { label })),
+        }
     }
 
-    fn parse_b_bgeu(&mut self) -> Result<Asm, String> {
-        // 先頭は SLL だとわかっているので、次の token に進める
+    // li rd, imm 疑似命令を parse するメソッド
+    // 値によって addi または lui と addi に展開する
+    fn parse_p_li(&mut self) -> Result<Asm, String> {
         self.next_token();
 
-        // 次の token は Number(x)
-        let rs1 = self.read_number_token()?;
-
-        // 次の token は Comma
+        let rd = self.read_register_token()?;
         self.read_token_kind(Comma)?;
+        let imm = self.read_number_token()?;
+        // 32bit の符号付き整数または符号なし整数として表せる値
+        if !(-0x8000_0000..=0xffff_ffff).contains(&imm) {
+            return Err(format!(
+                "Parser::parse_p_li: immediate {} does not fit in 32 bits",
+                imm
+            ));
+        }
+        self.read_token_kind(NewLine)?;
 
-        // 次の token は Number(x)
-        let rs2 = self.read_number_token()?;
+        Ok(Asm::new(AsmKind::LI { imm, rd }))
+    }
 
-        // 次の token は Comma
-        self.read_token_kind(Comma)?;
+    // la rd, label 疑似命令を parse するメソッド
+    // auipc と addi の二命令に展開する
+    // 定数の場合は li と同じように展開する
+    fn parse_p_la(&mut self) -> Result<Asm, String> {
+        self.next_token();
 
-        // 次の token は Number(x)またはSymbol(s)
+        let rd = self.read_register_token()?;
+        self.read_token_kind(Comma)?;
         let (imm, label) = self.read_number_or_symbol_token()?;
-
-        // 命令列の最後は改行文字
         self.read_token_kind(NewLine)?;
 
-        Ok(Asm {
-            kind: AsmKind::BGEU {
-                imm,
-                rs2,
-                rs1,
-                label,
-            },
-        })
+        match (imm, label) {
+            (_, Some(label)) => Ok(Asm::new(AsmKind::LA { rd, label })),
+            (Some(imm), None) => Ok(Asm::new(AsmKind::LI { imm, rd })),
+            (None, None) => Err("Parser::parse_p_la: address is missing".to_string()),
+        }
+    }
+}
+
+// 定数式の二項演算子の最大の優先順位
+const MAX_PRECEDENCE: usize = 10;
+
+// 二項演算子の優先順位を返す (値が小さいほど強く結合する)
+// C 言語の優先順位に合わせる
+fn precedence(op: &TokenKind) -> Option<usize> {
+    match op {
+        Star | Slash | Percent => Some(1),
+        Plus | Minus => Some(2),
+        Shl | Shr => Some(3),
+        Lt | Le | Gt | Ge => Some(4),
+        EqEq | Ne => Some(5),
+        Amp => Some(6),
+        Caret => Some(7),
+        Pipe => Some(8),
+        AndAnd => Some(9),
+        OrOr => Some(10),
+        _ => None,
     }
 }
 
+// 二項演算を計算する
+fn apply_binary(op: &TokenKind, lhs: isize, rhs: isize) -> Result<isize, String> {
+    let v = match op {
+        Star => lhs.wrapping_mul(rhs),
+        Slash | Percent if rhs == 0 => return Err("apply_binary: division by zero".to_string()),
+        Slash => lhs.wrapping_div(rhs),
+        Percent => lhs.wrapping_rem(rhs),
+        Plus => lhs.wrapping_add(rhs),
+        Minus => lhs.wrapping_sub(rhs),
+        Shl | Shr if !(0..64).contains(&rhs) => {
+            return Err(format!(
+                "apply_binary: shift amount {} is out of range",
+                rhs
+            ))
+        }
+        Shl => lhs << rhs,
+        Shr => lhs >> rhs,
+        Amp => lhs & rhs,
+        Caret => lhs ^ rhs,
+        Pipe => lhs | rhs,
+        // 比較と論理演算は真を 1、偽を 0 とする
+        Lt => (lhs < rhs) as isize,
+        Le => (lhs <= rhs) as isize,
+        Gt => (lhs > rhs) as isize,
+        Ge => (lhs >= rhs) as isize,
+        EqEq => (lhs == rhs) as isize,
+        Ne => (lhs != rhs) as isize,
+        AndAnd => (lhs != 0 && rhs != 0) as isize,
+        OrOr => (lhs != 0 || rhs != 0) as isize,
+        _ => return Err(format!("apply_binary: {:?} is not a binary operator", op)),
+    };
+    Ok(v)
+}
+
 #[cfg(test)]
 mod parser_tests {
-    use crate::inst::{Inst, InstType::*};
+    use crate::asm::{DataItem, Reloc, SymbolSize};
     use crate::lexer::*;
     use crate::parser::*;
-    use crate::token::TokenKind::*;
 
     // lw rd imm(rs1)
     #[test]
@@ -922,13 +2336,13 @@ mod parser_tests {
 
     #[test]
     fn test_parser_r_or() {
-        let s: &str = "or 0, 100, 521\n";
+        let s: &str = "or 0, 10, 21\n";
         let mut l = Lexer::new(s);
         let mut p = Parser::new(&mut l);
         let asm_kind = p.parse().unwrap().kind;
         let expect = AsmKind::OR {
-            rs2: 521,
-            rs1: 100,
+            rs2: 21,
+            rs1: 10,
             rd: 0,
         };
         assert_eq!(asm_kind, expect);
@@ -936,13 +2350,13 @@ mod parser_tests {
 
     #[test]
     fn test_parser_r_xor() {
-        let s: &str = "xor 24, 111, 666\n";
+        let s: &str = "xor 24, 11, 26\n";
         let mut l = Lexer::new(s);
         let mut p = Parser::new(&mut l);
         let asm_kind = p.parse().unwrap().kind;
         let expect = AsmKind::XOR {
-            rs2: 666,
-            rs1: 111,
+            rs2: 26,
+            rs1: 11,
             rd: 24,
         };
         assert_eq!(asm_kind, expect);
@@ -1175,4 +2589,1124 @@ mod parser_tests {
         };
         assert_eq!(asm_kind, expect);
     }
+
+    #[test]
+    fn test_parser_register_name() {
+        let s: &str = "add a0, x1, zero\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::ADD {
+            rs2: 0,
+            rs1: 1,
+            rd: 10,
+        };
+        assert_eq!(asm_kind, expect);
+    }
+
+    #[test]
+    fn test_parser_u_lui_hi() {
+        let s: &str = "lui a0, %hi(msg)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm = p.parse().unwrap();
+        assert_eq!(asm.kind, AsmKind::LUI { imm: 0, rd: 10 });
+        assert_eq!(asm.reloc, Some(Reloc::Hi("msg".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_parser_u_auipc() {
+        let s: &str = "auipc 5, 4096\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm = p.parse().unwrap();
+        assert_eq!(asm.kind, AsmKind::AUIPC { imm: 4096, rd: 5 });
+        assert_eq!(asm.reloc, None);
+    }
+
+    #[test]
+    fn test_parser_i_addi_lo() {
+        let s: &str = "addi a0, a0, %lo(msg)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm = p.parse().unwrap();
+        let expect = AsmKind::ADDI {
+            imm: 0,
+            rs1: 10,
+            rd: 10,
+        };
+        assert_eq!(asm.kind, expect);
+        assert_eq!(asm.reloc, Some(Reloc::Lo("msg".to_string(), 0)));
+    }
+
+    #[test]
+    fn test_parser_i_lw_pcrel_lo() {
+        let s: &str = "lw a1, %pcrel_lo(L1)(a1)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm = p.parse().unwrap();
+        let expect = AsmKind::LW {
+            imm: 0,
+            rs1: 11,
+            rd: 11,
+        };
+        assert_eq!(asm.kind, expect);
+        assert_eq!(asm.reloc, Some(Reloc::PcrelLo("L1".to_string())));
+    }
+
+    #[test]
+    fn test_parser_register_range() {
+        let mut l = Lexer::new("add 31, 0, 1\n");
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_ok());
+        // x32 と同じく、31 を超える番号と負の番号はエラー
+        for s in ["add 1, 40, 1\n", "add 1, 1, -1\n", "add x32, 1, 1\n"].iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parser_reloc_addend() {
+        let cases = [
+            (
+                "lui a0, %hi(buf+4)\n",
+                Some(Reloc::Hi("buf".to_string(), 4)),
+            ),
+            (
+                "addi a0, a0, %lo(buf -4)\n",
+                Some(Reloc::Lo("buf".to_string(), -4)),
+            ),
+            (
+                "auipc a0, %pcrel_hi(buf + 2 * 4)\n",
+                Some(Reloc::PcrelHi("buf".to_string(), 8)),
+            ),
+            // 定数は加数を足した値の上位と下位を即値にする
+            (".equ N, 0x7fc\nlui a0, %hi(N+4)\n", None),
+        ];
+        for (s, reloc) in cases {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let asm = p.parse().unwrap();
+            assert_eq!(asm.reloc, reloc, "{}", s);
+        }
+        let mut l = Lexer::new(".equ N, 0x7fc\nlui a0, %hi(N+4)\n");
+        let mut p = Parser::new(&mut l);
+        assert_eq!(p.parse().unwrap().kind, AsmKind::LUI { imm: 1, rd: 10 });
+
+        // %pcrel_lo は auipc のラベルだけを受け付ける
+        let mut l = Lexer::new("addi a0, a0, %pcrel_lo(L1+4)\n");
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
+
+    #[test]
+    fn test_parser_section() {
+        let cases = [
+            (".text\n", ".text"),
+            (".DATA\n", ".data"),
+            (".rodata\n", ".rodata"),
+            (".bss\n", ".bss"),
+            (".section .text.startup,\"ax\",@progbits\n", ".text"),
+            (".section .srodata.cst4,\"aM\",@progbits,4\n", ".rodata"),
+            (".section .sbss\n", ".bss"),
+            (".section .data\n", ".data"),
+            (
+                ".section \".note.GNU-stack\",\"\",@progbits\n",
+                ".note.GNU-stack",
+            ),
+            (
+                ".section .note.GNU-stack,\"\",@progbits\n",
+                ".note.GNU-stack",
+            ),
+            (".section .vectors\n", ".vectors"),
+        ];
+        for (s, name) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let expect = AsmKind::SECTION {
+                name: name.to_string(),
+            };
+            assert_eq!(p.parse().unwrap().kind, expect, "{}", s);
+        }
+
+        let mut l = Lexer::new(".section\n");
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
+
+    #[test]
+    fn test_parser_data() {
+        let item = |value: isize, label: Option<&str>| DataItem {
+            value,
+            label: label.map(|l| l.to_string()),
+            sub: None,
+        };
+        let diff = |value: isize, label: &str, sub: &str| DataItem {
+            value,
+            label: Some(label.to_string()),
+            sub: Some(sub.to_string()),
+        };
+        let cases = [
+            (
+                ".byte 1, -1, 0xff\n",
+                1,
+                vec![item(1, None), item(-1, None), item(0xff, None)],
+            ),
+            (".half 1 << 8\n", 2, vec![item(0x100, None)]),
+            (".short N * 2\n", 2, vec![item(8, None)]),
+            (
+                ".word f, f + 4, f -4\n",
+                4,
+                vec![item(0, Some("f")), item(4, Some("f")), item(-4, Some("f"))],
+            ),
+            (".long 1\n", 4, vec![item(1, None)]),
+            (".dword -1\n", 8, vec![item(-1, None)]),
+            (".quad N, f\n", 8, vec![item(4, None), item(0, Some("f"))]),
+            // 定数を先に書いた式とラベルの差
+            (
+                ".word 4 + f, N * 2 + f - 1, -1 + f\n",
+                4,
+                vec![item(4, Some("f")), item(7, Some("f")), item(-1, Some("f"))],
+            ),
+            (
+                ".word .Lx - .Ly, f - g + N, -g + f\n",
+                4,
+                vec![diff(0, ".Lx", ".Ly"), diff(4, "f", "g"), diff(0, "f", "g")],
+            ),
+            (".word 1 << 2 + 1\n", 4, vec![item(8, None)]),
+        ];
+        for (s, width, items) in cases.iter() {
+            let src = format!(".equ N, 4\n{}", s);
+            let mut l = Lexer::new(&src);
+            let mut p = Parser::new(&mut l);
+            let a = p.parse().unwrap();
+            let expect = AsmKind::DATA {
+                width: *width,
+                items: items.clone(),
+            };
+            assert_eq!(a.kind, expect, "{}", s);
+            assert_eq!(a.size(), width * items.len() as isize);
+        }
+
+        for s in [
+            ".word\n",
+            ".word 1,\n",
+            ".word f 4\n",
+            ".byte (1\n",
+            ".word f + g\n",
+            ".word -f\n",
+            ".word 4 - f\n",
+            ".word f - g - h\n",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parser_ascii() {
+        let cases = [
+            (".ascii \"ab\", \"c\"\n", b"abc".to_vec()),
+            (".asciz \"a\", \"b\"\n", b"a\0b\0".to_vec()),
+            (".string \"\\x41\\n\"\n", b"A\n\0".to_vec()),
+            (".ascii \"\"\n", vec![]),
+            // UTF-8 の文字はそのままのバイト列になる
+            (".asciz \"あ\"\n", vec![0xe3, 0x81, 0x82, 0]),
+        ];
+        for (s, bytes) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let a = p.parse().unwrap();
+            let expect = AsmKind::ASCII {
+                bytes: bytes.clone(),
+            };
+            assert_eq!(a.kind, expect, "{}", s);
+            assert_eq!(a.size(), bytes.len() as isize);
+        }
+
+        for s in [
+            ".ascii\n",
+            ".ascii 1\n",
+            ".asciz \"a\",\n",
+            ".string \"a\" \"b\"\n",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parser_align() {
+        let align = |align: isize, fill: Option<isize>, max: Option<isize>| AsmKind::ALIGN {
+            align,
+            fill,
+            max,
+        };
+        let cases = [
+            (".align 2\n", align(4, None, None)),
+            (".p2align 3, 0xff\n", align(8, Some(0xff), None)),
+            (".p2align 4,,8\n", align(16, None, Some(8))),
+            (".balign 16, -1, 4\n", align(16, Some(-1), Some(4))),
+            (".BALIGN 1\n", align(1, None, None)),
+        ];
+        for (s, expect) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(p.parse().unwrap().kind, *expect, "{}", s);
+        }
+
+        let errors = [
+            ".balign 3\n",
+            ".balign 0\n",
+            ".p2align 31\n",
+            ".p2align -1\n",
+            ".balign 4, 256\n",
+            ".balign 4, 0, -1\n",
+            ".align\n",
+        ];
+        for s in errors.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+
+        // エラーは引数を書いた行で報告する
+        for s in [".balign 4, 256\nnop\n", ".balign 4, 0, -1\nnop\n"].iter() {
+            let e = Parser::new(Lexer::new(s)).parse().unwrap_err();
+            assert!(e.starts_with("1:"), "{}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_parser_space() {
+        let space = |repeat: isize, size: isize, value: isize| AsmKind::SPACE {
+            repeat,
+            size,
+            value,
+        };
+        let cases = [
+            (".space 256\n", space(256, 1, 0)),
+            (".space 4, 0xff\n", space(4, 1, 0xff)),
+            (".skip 2, -1\n", space(2, 1, -1)),
+            (".zero 8\n", space(8, 1, 0)),
+            (".fill 3\n", space(3, 1, 0)),
+            (".fill 16, 4\n", space(16, 4, 0)),
+            (".fill 16, 4, 0xdeadbeef\n", space(16, 4, 0xdeadbeef)),
+        ];
+        for (s, expect) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let a = p.parse().unwrap();
+            assert_eq!(a.kind, *expect, "{}", s);
+        }
+
+        let errors = [
+            ".space\n",
+            ".space -1\n",
+            ".space 4, 256\n",
+            ".zero 4, 1\n",
+            ".fill 1, 9\n",
+            ".fill 1, 4, 0, 0\n",
+            ".space 0x100000001\n",
+            ".fill 0x7fffffffffffffff, 8, 0\n",
+        ];
+        for s in errors.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+
+        // エラーは引数を書いた行で報告する
+        for s in [
+            ".data\n.fill 1, 16, 1\nnop\n",
+            ".data\n.space 0x7fffffffffffffff\nnop\n",
+        ]
+        .iter()
+        {
+            let mut p = Parser::new(Lexer::new(s));
+            p.parse().unwrap();
+            let e = p.parse().unwrap_err();
+            assert!(e.starts_with("2:"), "{}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_parser_org() {
+        let cases = [
+            (".org 0x100\n", 0x100, None),
+            (".org 0x10 * 4, 0xff\n", 0x40, Some(0xff)),
+        ];
+        for (s, addr, fill) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let expect = AsmKind::ORG {
+                addr: *addr,
+                fill: *fill,
+            };
+            assert_eq!(p.parse().unwrap().kind, expect, "{}", s);
+        }
+
+        for s in [
+            ".org\n",
+            ".org 4, 256\n",
+            ".org 4, 0, 0\n",
+            ".org -1\n",
+            ".org 0x100000000\n",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+        assert!(Parser::new(Lexer::new(".org 0xffffffff\n")).parse().is_ok());
+
+        // エラーは引数を書いた行で報告する
+        for s in [".org 4, 256\nnop\n", ".org 0x7fffffffffffffff\nnop\n"].iter() {
+            let e = Parser::new(Lexer::new(s)).parse().unwrap_err();
+            assert!(e.starts_with("1:"), "{}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_parser_bind_size() {
+        let names = |ns: &[&str]| ns.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let diff = |end: Option<&str>, start: &str| SymbolSize::Diff {
+            end: end.map(str::to_string),
+            start: start.to_string(),
+        };
+        let cases = [
+            (
+                ".globl main\n",
+                AsmKind::BIND {
+                    binding: Binding::Global,
+                    names: names(&["main"]),
+                },
+            ),
+            (
+                ".extern putc, getc\n",
+                AsmKind::BIND {
+                    binding: Binding::Global,
+                    names: names(&["putc", "getc"]),
+                },
+            ),
+            (
+                ".local tmp\n",
+                AsmKind::BIND {
+                    binding: Binding::Local,
+                    names: names(&["tmp"]),
+                },
+            ),
+            (
+                ".weak handler\n",
+                AsmKind::BIND {
+                    binding: Binding::Weak,
+                    names: names(&["handler"]),
+                },
+            ),
+            (
+                ".size table, 4 * 4\n",
+                AsmKind::SIZE {
+                    name: "table".to_string(),
+                    size: SymbolSize::Const(16),
+                },
+            ),
+            (
+                ".size main, .-main\n",
+                AsmKind::SIZE {
+                    name: "main".to_string(),
+                    size: diff(None, "main"),
+                },
+            ),
+            (
+                ".size main, .Lfunc_end0 - main\n",
+                AsmKind::SIZE {
+                    name: "main".to_string(),
+                    size: diff(Some(".Lfunc_end0"), "main"),
+                },
+            ),
+        ];
+        for (s, expect) in cases.iter() {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(&p.parse().unwrap().kind, expect, "{}", s);
+        }
+
+        for s in [
+            ".globl\n",
+            ".globl 1\n",
+            ".weak a,\n",
+            ".size main\n",
+            ".size main, . - 4\n",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_parser_mem_operand() {
+        let cases = [
+            (
+                "lw a0, (a1)\n",
+                AsmKind::LW {
+                    imm: 0,
+                    rs1: 11,
+                    rd: 10,
+                },
+                None,
+            ),
+            (
+                "sw a0, (sp)\n",
+                AsmKind::SW {
+                    imm: 0,
+                    rs2: 10,
+                    rs1: 2,
+                },
+                None,
+            ),
+            (
+                "lw a0, (4)(a1)\n",
+                AsmKind::LW {
+                    imm: 4,
+                    rs1: 11,
+                    rd: 10,
+                },
+                None,
+            ),
+            (
+                "lw a0, table(a1)\n",
+                AsmKind::LWSYM {
+                    rd: 10,
+                    rs1: 11,
+                    label: "table".to_string(),
+                },
+                None,
+            ),
+            (
+                "sw a0, table(a1), t0\n",
+                AsmKind::SWSYM {
+                    rs2: 10,
+                    rs1: 11,
+                    rt: 5,
+                    label: "table".to_string(),
+                },
+                None,
+            ),
+            (
+                "lw a0, count\n",
+                AsmKind::LWSYM {
+                    rd: 10,
+                    rs1: 0,
+                    label: "count".to_string(),
+                },
+                None,
+            ),
+            (
+                "sw a0, count, t0\n",
+                AsmKind::SWSYM {
+                    rs2: 10,
+                    rs1: 0,
+                    rt: 5,
+                    label: "count".to_string(),
+                },
+                None,
+            ),
+        ];
+        for (src, expect, reloc) in cases.iter() {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            let asm = p.parse().unwrap();
+            assert_eq!(&asm.kind, expect, "{}", src);
+            assert_eq!(&asm.reloc, reloc, "{}", src);
+        }
+
+        // 定数は従来どおり即値として扱う
+        let mut l = Lexer::new(".equ OFF, 8\nlw a0, OFF(a1)\n");
+        let mut p = Parser::new(&mut l);
+        assert_eq!(
+            p.parse().unwrap().kind,
+            AsmKind::LW {
+                imm: 8,
+                rs1: 11,
+                rd: 10
+            }
+        );
+        // label(rs1) はアドレスを作るレジスタと rs1 を別にする
+        for src in [
+            "lw a0, a1\n",
+            "sw a0, count\n",
+            "lw a0, (a1\n",
+            "sw a0, table(a1)\n",
+            "lw a1, table(a1)\n",
+            "sw a0, table(a1), a1\n",
+            "sw a1, table, a1\n",
+            "sw a1, table(a2), a1\n",
+        ]
+        .iter()
+        {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            assert!(p.parse().is_err(), "{}", src);
+        }
+        // レジスタのエラーは命令を書いた行で報告する
+        for src in [
+            "lw a1, table(a1)\nnop\n",
+            "sw a0, table(a1), a1\nnop\n",
+            "sw a1, table, a1\nnop\n",
+        ]
+        .iter()
+        {
+            let e = Parser::new(Lexer::new(src)).parse().unwrap_err();
+            assert!(e.starts_with("1:"), "{}: {}", src, e);
+        }
+    }
+
+    #[test]
+    fn test_parser_unknown_reloc() {
+        let s: &str = "addi a0, a0, %foo(msg)\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
+
+    #[test]
+    fn test_parser_skip_blank_lines() {
+        let s: &str = "\n\nloop:\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::LABEL {
+            l: "loop".to_string(),
+        };
+        assert_eq!(asm_kind, expect);
+        assert_eq!(p.parse().unwrap().kind, AsmKind::EOASM);
+    }
+
+    #[test]
+    fn test_parser_local_label() {
+        let s: &str = "1:\nbeq 1, 2, 1b\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::LABEL { l: "1".to_string() };
+        assert_eq!(asm_kind, expect);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::BEQ {
+            imm: None,
+            rs2: 2,
+            rs1: 1,
+            label: Some("1b".to_string()),
+        };
+        assert_eq!(asm_kind, expect);
+    }
+
+    #[test]
+    fn test_parser_equ() {
+        let s: &str =
+            ".equ UART_BASE, 0x10000000\nLED = 0x20\nlui a0, %hi(UART_BASE)\naddi a1, zero, LED\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm = p.parse().unwrap();
+        assert_eq!(
+            asm.kind,
+            AsmKind::LUI {
+                imm: 0x10000,
+                rd: 10
+            }
+        );
+        assert_eq!(asm.reloc, None);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::ADDI {
+            imm: 0x20,
+            rs1: 0,
+            rd: 11,
+        };
+        assert_eq!(asm_kind, expect);
+    }
+
+    #[test]
+    fn test_parser_equ_redefinition() {
+        let s: &str = ".equ N, 1\n.equ N, 2\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
+
+    #[test]
+    fn test_parser_set_redefinition() {
+        let s: &str = ".set N, 1\n.set N, N + 1\nN = N * 3\naddi 1, 1, N\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::ADDI {
+            imm: 6,
+            rs1: 1,
+            rd: 1,
+        };
+        assert_eq!(asm_kind, expect);
+    }
+
+    #[test]
+    fn test_parser_use_before_definition() {
+        // .equ の定数は定義より前でも使える
+        let s: &str = "addi 1, 1, N\n.equ N, M + 1\n.equ M, 2\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::ADDI {
+            imm: 3,
+            rs1: 1,
+            rd: 1,
+        };
+        assert_eq!(asm_kind, expect);
+
+        let cases = [
+            // .set, = の定数は後で値が変わるので定義より前では使えない
+            (
+                "addi 1, 1, N\n.set N, 1\n",
+                "1:12: Parser::parse_expr_primary: \"N\" is used before definition",
+            ),
+            ("addi 1, 1, N\nN = 1\n", "\"N\" is used before definition"),
+            (
+                "addi 1, 1, N\n.equ N, S\n.set S, 1\n",
+                "\"N\" is used before definition",
+            ),
+            // 循環した定義
+            (
+                "addi 1, 1, A\n.equ A, B\n.equ B, A\n",
+                "\"A\" is used before definition",
+            ),
+            (
+                "addi 1, 1, N\n",
+                "1:12: Parser::parse_expr_primary: \"N\" is not defined",
+            ),
+            (
+                "addi 1, 1, X + 1\nX:\n",
+                "label \"X\" cannot be used as a constant",
+            ),
+            (
+                ".equ N, X\nX:\n",
+                "label \"X\" cannot be used as a constant",
+            ),
+        ];
+        for (s, msg) in cases {
+            let mut l = Lexer::new(s);
+            let mut p = Parser::new(&mut l);
+            let e = p.parse().unwrap_err();
+            assert!(e.contains(msg), "{:?}: {}", s, e);
+        }
+    }
+
+    #[test]
+    fn test_parser_expr() {
+        let cases = [
+            ("1 + 2 * 3", 7),
+            ("(1 + 2) * 3", 9),
+            ("8-1<<2", 28),
+            ("8 - 1 << 2", 28),
+            ("-(4 + 4)", -8),
+            ("~0 & 0xff", 0xff),
+            ("1 | 2 ^ 3 & 4", 3),
+            ("7 / 2 + 7 % 2", 4),
+            ("N-1", 9),
+            ("N == 10", 1),
+            ("N != 10", 0),
+            ("1 + 1 < 3 && N >= 10", 1),
+            ("!N || 2 <= 1", 0),
+            ("1 & 3 == 3", 1),
+        ];
+        for (src, expect) in cases.iter() {
+            let s = format!(".equ N, 10\naddi 1, 1, {}\n", src);
+            let mut l = Lexer::new(&s);
+            let mut p = Parser::new(&mut l);
+            let asm_kind = p.parse().unwrap().kind;
+            let expect = AsmKind::ADDI {
+                imm: *expect,
+                rs1: 1,
+                rd: 1,
+            };
+            assert_eq!(asm_kind, expect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parser_expr_division_by_zero() {
+        let s: &str = "addi 1, 1, 1 / 0\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
+
+    #[test]
+    fn test_parser_b_constant_offset() {
+        let s: &str = ".equ SKIP, 8\nbeq 1, 2, SKIP\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        let asm_kind = p.parse().unwrap().kind;
+        let expect = AsmKind::BEQ {
+            imm: Some(8),
+            rs2: 2,
+            rs1: 1,
+            label: None,
+        };
+        assert_eq!(asm_kind, expect);
+    }
+
+    #[test]
+    fn test_parser_i_logical_imm() {
+        let cases = [
+            (
+                "andi 1, 2, 0xff\n",
+                AsmKind::ANDI {
+                    imm: 0xff,
+                    rs1: 2,
+                    rd: 1,
+                },
+            ),
+            (
+                "ori 1, 2, 3\n",
+                AsmKind::ORI {
+                    imm: 3,
+                    rs1: 2,
+                    rd: 1,
+                },
+            ),
+            (
+                "xori 1, 2, -1\n",
+                AsmKind::XORI {
+                    imm: -1,
+                    rs1: 2,
+                    rd: 1,
+                },
+            ),
+        ];
+        for (src, expect) in cases.iter() {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(&p.parse().unwrap().kind, expect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parser_jal_jalr() {
+        let cases = [
+            (
+                "jal loop\n",
+                AsmKind::JAL {
+                    imm: None,
+                    rd: 1,
+                    label: Some("loop".to_string()),
+                },
+            ),
+            (
+                "jal t0, 8\n",
+                AsmKind::JAL {
+                    imm: Some(8),
+                    rd: 5,
+                    label: None,
+                },
+            ),
+            (
+                "jalr t0\n",
+                AsmKind::JALR {
+                    imm: 0,
+                    rs1: 5,
+                    rd: 1,
+                },
+            ),
+            (
+                "jalr x0, t0\n",
+                AsmKind::JALR {
+                    imm: 0,
+                    rs1: 5,
+                    rd: 0,
+                },
+            ),
+            (
+                "jalr x0, t0, 4\n",
+                AsmKind::JALR {
+                    imm: 4,
+                    rs1: 5,
+                    rd: 0,
+                },
+            ),
+            (
+                "jalr ra, -4(t0)\n",
+                AsmKind::JALR {
+                    imm: -4,
+                    rs1: 5,
+                    rd: 1,
+                },
+            ),
+        ];
+        for (src, expect) in cases.iter() {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(&p.parse().unwrap().kind, expect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parser_case_insensitive() {
+        // 命令とレジスタ名は大文字と小文字を区別せず、ラベルは区別する
+        let cases = [
+            (
+                "ADDI A0, Zero, 1\n",
+                AsmKind::ADDI {
+                    imm: 1,
+                    rs1: 0,
+                    rd: 10,
+                },
+            ),
+            (
+                "Jal X5, Loop_1\n",
+                AsmKind::JAL {
+                    imm: None,
+                    rd: 5,
+                    label: Some("Loop_1".to_string()),
+                },
+            ),
+        ];
+        for (src, expect) in cases.iter() {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(&p.parse().unwrap().kind, expect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parser_pseudo() {
+        let label = || Some("L".to_string());
+        let cases = [
+            (
+                "nop\n",
+                AsmKind::ADDI {
+                    imm: 0,
+                    rs1: 0,
+                    rd: 0,
+                },
+            ),
+            (
+                "mv a0, a1\n",
+                AsmKind::ADDI {
+                    imm: 0,
+                    rs1: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                "not a0, a1\n",
+                AsmKind::XORI {
+                    imm: -1,
+                    rs1: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                "neg a0, a1\n",
+                AsmKind::SUB {
+                    rs2: 11,
+                    rs1: 0,
+                    rd: 10,
+                },
+            ),
+            (
+                "seqz a0, a1\n",
+                AsmKind::SLTIU {
+                    imm: 1,
+                    rs1: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                "snez a0, a1\n",
+                AsmKind::SLTU {
+                    rs2: 11,
+                    rs1: 0,
+                    rd: 10,
+                },
+            ),
+            (
+                "sltz a0, a1\n",
+                AsmKind::SLT {
+                    rs2: 0,
+                    rs1: 11,
+                    rd: 10,
+                },
+            ),
+            (
+                "sgtz a0, a1\n",
+                AsmKind::SLT {
+                    rs2: 11,
+                    rs1: 0,
+                    rd: 10,
+                },
+            ),
+            (
+                "beqz a0, L\n",
+                AsmKind::BEQ {
+                    imm: None,
+                    rs2: 0,
+                    rs1: 10,
+                    label: label(),
+                },
+            ),
+            (
+                "bnez a0, L\n",
+                AsmKind::BNE {
+                    imm: None,
+                    rs2: 0,
+                    rs1: 10,
+                    label: label(),
+                },
+            ),
+            (
+                "blez a0, L\n",
+                AsmKind::BGE {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 0,
+                    label: label(),
+                },
+            ),
+            (
+                "bgez a0, L\n",
+                AsmKind::BGE {
+                    imm: None,
+                    rs2: 0,
+                    rs1: 10,
+                    label: label(),
+                },
+            ),
+            (
+                "bltz a0, L\n",
+                AsmKind::BLT {
+                    imm: None,
+                    rs2: 0,
+                    rs1: 10,
+                    label: label(),
+                },
+            ),
+            (
+                "bgtz a0, L\n",
+                AsmKind::BLT {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 0,
+                    label: label(),
+                },
+            ),
+            (
+                "bgt a0, a1, L\n",
+                AsmKind::BLT {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 11,
+                    label: label(),
+                },
+            ),
+            (
+                "ble a0, a1, L\n",
+                AsmKind::BGE {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 11,
+                    label: label(),
+                },
+            ),
+            (
+                "bgtu a0, a1, L\n",
+                AsmKind::BLTU {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 11,
+                    label: label(),
+                },
+            ),
+            (
+                "bleu a0, a1, L\n",
+                AsmKind::BGEU {
+                    imm: None,
+                    rs2: 10,
+                    rs1: 11,
+                    label: label(),
+                },
+            ),
+            (
+                "j L\n",
+                AsmKind::JAL {
+                    imm: None,
+                    rd: 0,
+                    label: label(),
+                },
+            ),
+            (
+                "jr t0\n",
+                AsmKind::JALR {
+                    imm: 0,
+                    rs1: 5,
+                    rd: 0,
+                },
+            ),
+            (
+                "ret\n",
+                AsmKind::JALR {
+                    imm: 0,
+                    rs1: 1,
+                    rd: 0,
+                },
+            ),
+            (
+                "call L\n",
+                AsmKind::CALL {
+                    label: "L".to_string(),
+                },
+            ),
+            (
+                "tail L\n",
+                AsmKind::TAIL {
+                    label: "L".to_string(),
+                },
+            ),
+            (
+                "li a0, 0x12345\n",
+                AsmKind::LI {
+                    imm: 0x12345,
+                    rd: 10,
+                },
+            ),
+            (
+                "la a0, L\n",
+                AsmKind::LA {
+                    rd: 10,
+                    label: "L".to_string(),
+                },
+            ),
+        ];
+        for (src, expect) in cases.iter() {
+            let mut l = Lexer::new(src);
+            let mut p = Parser::new(&mut l);
+            assert_eq!(&p.parse().unwrap().kind, expect, "{}", src);
+        }
+    }
+
+    #[test]
+    fn test_parser_li_out_of_range() {
+        let s: &str = "li a0, 0x100000000\n";
+        let mut l = Lexer::new(s);
+        let mut p = Parser::new(&mut l);
+        assert!(p.parse().is_err());
+    }
 }
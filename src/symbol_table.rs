@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::label_table::is_local_label;
+use crate::token::Loc;
+
+// シンボルの結合
+// Global, Weak のシンボルは他のファイルから参照でき、未定義なら他のファイルで定義されたものを使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Local,
+    Global,
+    Weak,
+}
+
+// シンボル表の一つのシンボル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    // アドレス (セクションの先頭アドレスに先頭からの位置を足した値)
+    pub value: isize,
+    // 定義されたセクション、未定義のシンボルは None
+    pub section: Option<String>,
+    pub binding: Binding,
+    // .size で指定した大きさ
+    pub size: Option<isize>,
+    // 定義した位置、未定義のシンボルは最初に宣言した位置
+    pub loc: Loc,
+}
+
+impl Symbol {
+    // このファイルで定義されているかを返す
+    pub fn is_defined(&self) -> bool {
+        self.section.is_some()
+    }
+
+    // 他のファイルで定義されるシンボルかを返す
+    // .globl, .extern, .weak で宣言して定義していないシンボルは、参照を再配置として残す
+    pub fn is_external(&self) -> bool {
+        !self.is_defined() && self.binding != Binding::Local
+    }
+}
+
+// ラベルの名前からシンボルへの対応表
+// 数字ラベルは label_table::local_label_key のキーで登録する
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    // SymbolTableのコンストラクター
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ラベルを定義する
+    // 先に .globl などで宣言されていれば、その binding のまま定義する
+    pub fn define(
+        &mut self,
+        name: &str,
+        value: isize,
+        section: &str,
+        loc: Loc,
+    ) -> Result<(), String> {
+        match self.symbols.get_mut(name) {
+            Some(sym) if sym.is_defined() => Err(format!(
+                "SymbolTable::define: label {:?} is already defined at {}",
+                name, sym.loc
+            )),
+            Some(sym) => {
+                sym.value = value;
+                sym.section = Some(section.to_string());
+                sym.loc = loc;
+                Ok(())
+            }
+            None => {
+                let sym = Symbol {
+                    value,
+                    section: Some(section.to_string()),
+                    binding: Binding::Local,
+                    size: None,
+                    loc,
+                };
+                self.symbols.insert(name.to_string(), sym);
+                Ok(())
+            }
+        }
+    }
+
+    // シンボルの binding を宣言する
+    // GNU as と同じく、同じシンボルを何度宣言しても後から宣言した binding にする
+    pub fn declare(&mut self, name: &str, binding: Binding, loc: Loc) {
+        let sym = self.symbols.entry(name.to_string()).or_insert(Symbol {
+            value: 0,
+            section: None,
+            binding,
+            size: None,
+            loc,
+        });
+        sym.binding = binding;
+    }
+
+    // シンボルの大きさを設定する
+    pub fn set_size(&mut self, name: &str, size: isize) -> Result<(), String> {
+        match self.symbols.get_mut(name) {
+            Some(sym) if sym.is_defined() => {
+                sym.size = Some(size);
+                Ok(())
+            }
+            _ => Err(format!(
+                "SymbolTable::set_size: label {:?} is not defined",
+                name
+            )),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.get(name)
+    }
+
+    // 定義されたシンボルのアドレスを返す
+    pub fn value(&self, name: &str) -> Option<isize> {
+        self.get(name)
+            .filter(|sym| sym.is_defined())
+            .map(|sym| sym.value)
+    }
+
+    // 他のファイルで定義されるシンボルかを返す
+    pub fn is_external(&self, name: &str) -> bool {
+        self.get(name).is_some_and(Symbol::is_external)
+    }
+
+    // Global, Weak のシンボルがあるかを返す
+    pub fn has_global(&self) -> bool {
+        self.symbols
+            .values()
+            .any(|sym| sym.binding != Binding::Local)
+    }
+
+    // 数字ラベルを除いたシンボルを、セクション、アドレス、名前の順に並べて返す
+    // 未定義のシンボルは最後に並べる
+    pub fn symbols(&self) -> Vec<(&str, &Symbol)> {
+        let mut symbols = self
+            .symbols
+            .iter()
+            .filter(|(name, _)| !name.split_once(':').is_some_and(|(l, _)| is_local_label(l)))
+            .map(|(name, sym)| (name.as_str(), sym))
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|(name, sym)| (sym.section.is_none(), &sym.section, sym.value, *name));
+        symbols
+    }
+}
+
+// アドレス, 大きさ, binding, セクション, 名前 を一行ずつ並べる
+// 未定義のシンボルのセクションは *UND* にする
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, sym) in self.symbols() {
+            writeln!(
+                f,
+                "{:#010x} {:>6} {:<6} {:<8} {}",
+                sym.value,
+                sym.size.unwrap_or(0),
+                sym.binding,
+                sym.section.as_deref().unwrap_or("*UND*"),
+                name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Local => "local",
+            Self::Global => "global",
+            Self::Weak => "weak",
+        };
+        f.pad(name)
+    }
+}
+
+// 再配置の種類
+// 名前は RISC-V の ELF の再配置と同じにする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocType {
+    Branch,
+    Jal,
+    // auipc と jalr の組
+    Call,
+    PcrelHi20,
+    PcrelLo12I,
+    PcrelLo12S,
+    Hi20,
+    Lo12I,
+    Lo12S,
+    Abs32,
+    Abs64,
+}
+
+impl fmt::Display for RelocType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Branch => "R_RISCV_BRANCH",
+            Self::Jal => "R_RISCV_JAL",
+            Self::Call => "R_RISCV_CALL",
+            Self::PcrelHi20 => "R_RISCV_PCREL_HI20",
+            Self::PcrelLo12I => "R_RISCV_PCREL_LO12_I",
+            Self::PcrelLo12S => "R_RISCV_PCREL_LO12_S",
+            Self::Hi20 => "R_RISCV_HI20",
+            Self::Lo12I => "R_RISCV_LO12_I",
+            Self::Lo12S => "R_RISCV_LO12_S",
+            Self::Abs32 => "R_RISCV_32",
+            Self::Abs64 => "R_RISCV_64",
+        };
+        f.pad(name)
+    }
+}
+
+// 他のファイルで定義されるシンボルの参照
+// 参照する命令やデータの該当する bit は 0 にしておき、リンク時に symbol + addend で埋める
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub section: String,
+    // 参照する命令やデータのアドレス
+    pub offset: isize,
+    pub ty: RelocType,
+    pub symbol: String,
+    pub addend: isize,
+}
+
+impl fmt::Display for Relocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<8} {:#010x} {:<20} {}",
+            self.section, self.offset, self.ty, self.symbol
+        )?;
+        if self.addend != 0 {
+            write!(f, "{:+}", self.addend)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod symbol_table_tests {
+    use crate::symbol_table::*;
+
+    fn loc(line: usize) -> Loc {
+        Loc {
+            line,
+            col: 1,
+            ..Loc::default()
+        }
+    }
+
+    #[test]
+    fn test_symbol_table() {
+        let mut st = SymbolTable::new();
+        // 宣言してから定義しても、定義してから宣言してもよい
+        st.declare("main", Binding::Global, loc(1));
+        st.define("main", 0x10, ".text", loc(3)).unwrap();
+        st.define("table", 0x1000, ".data", loc(5)).unwrap();
+        st.declare("table", Binding::Weak, loc(6));
+        st.define("1:0", 0x14, ".text", loc(7)).unwrap();
+        st.declare("putc", Binding::Global, loc(8));
+        st.set_size("main", 8).unwrap();
+
+        let main = st.get("main").unwrap();
+        assert_eq!(
+            (main.value, main.binding, main.size),
+            (0x10, Binding::Global, Some(8))
+        );
+        assert_eq!(main.loc, loc(3));
+        assert_eq!(st.get("table").unwrap().binding, Binding::Weak);
+        assert_eq!(st.value("1:0"), Some(0x14));
+        // 未定義の Global のシンボルは他のファイルで定義される
+        assert_eq!(st.value("putc"), None);
+        assert!(st.is_external("putc"));
+        assert!(!st.is_external("main"));
+        assert!(st.has_global());
+
+        assert_eq!(
+            st.to_string(),
+            "0x00001000      0 weak   .data    table\n\
+             0x00000010      8 global .text    main\n\
+             0x00000000      0 global *UND*    putc\n"
+        );
+    }
+
+    #[test]
+    fn test_symbol_table_errors() {
+        let mut st = SymbolTable::new();
+        st.define("loop", 0, ".text", loc(1)).unwrap();
+        let e = st.define("loop", 4, ".text", loc(2)).unwrap_err();
+        // 最初に定義した位置を示す
+        assert!(e.contains("already defined at 1:1"), "{}", e);
+
+        st.declare("f", Binding::Local, loc(3));
+        assert!(!st.is_external("f"));
+        assert!(st.set_size("f", 4).is_err());
+        assert!(!st.has_global());
+    }
+
+    #[test]
+    fn test_relocation_display() {
+        let r = Relocation {
+            section: ".data".to_string(),
+            offset: 0x1004,
+            ty: RelocType::Abs32,
+            symbol: "table".to_string(),
+            addend: 8,
+        };
+        assert_eq!(
+            r.to_string(),
+            ".data    0x00001004 R_RISCV_32           table+8"
+        );
+    }
+}
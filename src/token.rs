@@ -1,5 +1,8 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result};
+use std::path::PathBuf;
+use std::rc::Rc;
 
 static KEYWORDS: Lazy<HashMap<&str, TokenKind>> = Lazy::new(|| {
     [
@@ -31,12 +34,118 @@ static KEYWORDS: Lazy<HashMap<&str, TokenKind>> = Lazy::new(|| {
         ("bge", TokenKind::BGE),
         ("bltu", TokenKind::BLTU),
         ("bgeu", TokenKind::BGEU),
+        ("lui", TokenKind::LUI),
+        ("auipc", TokenKind::AUIPC),
+        ("jal", TokenKind::JAL),
+        ("jalr", TokenKind::JALR),
+        // 疑似命令
+        ("nop", TokenKind::NOP),
+        ("mv", TokenKind::MV),
+        ("not", TokenKind::NOT),
+        ("neg", TokenKind::NEG),
+        ("seqz", TokenKind::SEQZ),
+        ("snez", TokenKind::SNEZ),
+        ("sltz", TokenKind::SLTZ),
+        ("sgtz", TokenKind::SGTZ),
+        ("beqz", TokenKind::BEQZ),
+        ("bnez", TokenKind::BNEZ),
+        ("blez", TokenKind::BLEZ),
+        ("bgez", TokenKind::BGEZ),
+        ("bltz", TokenKind::BLTZ),
+        ("bgtz", TokenKind::BGTZ),
+        ("bgt", TokenKind::BGT),
+        ("ble", TokenKind::BLE),
+        ("bgtu", TokenKind::BGTU),
+        ("bleu", TokenKind::BLEU),
+        ("j", TokenKind::J),
+        ("jr", TokenKind::JR),
+        ("ret", TokenKind::RET),
+        ("call", TokenKind::CALL),
+        ("tail", TokenKind::TAIL),
+        ("li", TokenKind::LI),
+        ("la", TokenKind::LA),
+        // ディレクティブ
+        (".equ", TokenKind::EQU),
+        (".set", TokenKind::SET),
+        (".macro", TokenKind::MACRO),
+        (".endm", TokenKind::ENDM),
+        (".include", TokenKind::INCLUDE),
+        (".rept", TokenKind::REPT),
+        (".irp", TokenKind::IRP),
+        (".irpc", TokenKind::IRPC),
+        (".endr", TokenKind::ENDR),
+        (".if", TokenKind::IF),
+        (".ifdef", TokenKind::IFDEF),
+        (".ifndef", TokenKind::IFNDEF),
+        (".elseif", TokenKind::ELSEIF),
+        (".else", TokenKind::ELSE),
+        (".endif", TokenKind::ENDIF),
+        (".error", TokenKind::ERROR),
+        (".warning", TokenKind::WARNING),
+        (".reg", TokenKind::REG),
+        (".equr", TokenKind::EQUR),
+        (".unreg", TokenKind::UNREG),
+        (".struct", TokenKind::STRUCT),
+        (".field", TokenKind::FIELD),
+        (".ends", TokenKind::ENDS),
+        (".while", TokenKind::WHILE),
+        (".endw", TokenKind::ENDW),
+        (".loop", TokenKind::LOOP),
+        (".endl", TokenKind::ENDL),
+        (".text", TokenKind::TEXT),
+        (".data", TokenKind::DATA),
+        (".rodata", TokenKind::RODATA),
+        (".bss", TokenKind::BSS),
+        (".section", TokenKind::SECTION),
+        (".byte", TokenKind::BYTE),
+        (".half", TokenKind::HALF),
+        (".short", TokenKind::SHORT),
+        (".word", TokenKind::WORD),
+        (".long", TokenKind::LONG),
+        (".dword", TokenKind::DWORD),
+        (".quad", TokenKind::QUAD),
+        (".ascii", TokenKind::ASCII),
+        (".asciz", TokenKind::ASCIZ),
+        (".string", TokenKind::STRING),
+        (".align", TokenKind::ALIGN),
+        (".balign", TokenKind::BALIGN),
+        (".p2align", TokenKind::P2ALIGN),
+        (".space", TokenKind::SPACE),
+        (".skip", TokenKind::SKIP),
+        (".zero", TokenKind::ZERO),
+        (".fill", TokenKind::FILL),
+        (".org", TokenKind::ORG),
+        (".memory", TokenKind::MEMORY),
+        (".incbin", TokenKind::INCBIN),
+        (".globl", TokenKind::GLOBL),
+        (".global", TokenKind::GLOBAL),
+        (".local", TokenKind::LOCAL),
+        (".extern", TokenKind::EXTERN),
+        (".weak", TokenKind::WEAK),
+        (".size", TokenKind::SIZE),
     ]
     .iter()
     .cloned()
     .collect::<HashMap<&str, TokenKind>>()
 });
 
+// レジスタ名 (x0 ~ x31 と ABI 名) とレジスタ番号の対応表
+static REGISTERS: Lazy<HashMap<String, isize>> = Lazy::new(|| {
+    let abi_names = [
+        "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+        "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+        "t5", "t6",
+    ];
+    let mut registers = HashMap::new();
+    for (i, name) in abi_names.iter().enumerate() {
+        registers.insert(name.to_string(), i as isize);
+        registers.insert(format!("x{}", i), i as isize);
+    }
+    // s0 の別名
+    registers.insert("fp".to_string(), 8);
+    registers
+});
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TokenKind {
     NewLine,        // '\n', '\r
@@ -46,8 +155,34 @@ pub enum TokenKind {
     RParen,         // ")"
     Symbol(String), // "lw"
     Number(isize),  // 123...
+    Reloc(String),  // "%hi", "%pcrel_lo"...
+    Equal,          // "="
+    Plus,           // "+"
+    Minus,          // "-"
+    Star,           // "*"
+    Slash,          // "/"
+    Percent,        // "%"
+    Shl,            // "<<"
+    Shr,            // ">>"
+    Amp,            // "&"
+    Pipe,           // "|"
+    Caret,          // "^"
+    Tilde,          // "~"
+    At,             // "@"
+    Dot,            // "." (現在のアドレス)
+    Bang,           // "!"
+    EqEq,           // "=="
+    Ne,             // "!="
+    Lt,             // "<"
+    Le,             // "<="
+    Gt,             // ">"
+    Ge,             // ">="
+    AndAnd,         // "&&"
+    OrOr,           // "||"
+    Str(Vec<u8>),   // "\"...\"" (エスケープを展開したバイト列)
     EOF,
     ILEGAL,
+    Error(String), // 閉じていない文字列などの字句解析のエラー
 
     // オペコード
     // I形式
@@ -57,6 +192,10 @@ pub enum TokenKind {
     // S形式
     SW, // sw
 
+    // U形式
+    LUI,   // lui
+    AUIPC, // auipc
+
     // R形式
     ADD,   // add
     SUB,   // sub
@@ -83,17 +222,260 @@ pub enum TokenKind {
     BGE,
     BLTU,
     BGEU,
+    // J形式
+    JAL,  // jal
+    JALR, // jalr
+
+    // 疑似命令
+    NOP,  // nop
+    MV,   // mv
+    NOT,  // not
+    NEG,  // neg
+    SEQZ, // seqz
+    SNEZ, // snez
+    SLTZ, // sltz
+    SGTZ, // sgtz
+    BEQZ, // beqz
+    BNEZ, // bnez
+    BLEZ, // blez
+    BGEZ, // bgez
+    BLTZ, // bltz
+    BGTZ, // bgtz
+    BGT,  // bgt
+    BLE,  // ble
+    BGTU, // bgtu
+    BLEU, // bleu
+    J,    // j
+    JR,   // jr
+    RET,  // ret
+    CALL, // call
+    TAIL, // tail
+    LI,   // li
+    LA,   // la
+
+    // ディレクティブ
+    EQU,     // .equ
+    SET,     // .set
+    MACRO,   // .macro
+    ENDM,    // .endm
+    INCLUDE, // .include
+    REPT,    // .rept
+    IRP,     // .irp
+    IRPC,    // .irpc
+    ENDR,    // .endr
+    REG,     // .reg
+    EQUR,    // .equr
+    UNREG,   // .unreg
+    STRUCT,  // .struct
+    FIELD,   // .field
+    ENDS,    // .ends
+    TEXT,    // .text
+    DATA,    // .data
+    RODATA,  // .rodata
+    BSS,     // .bss
+    SECTION, // .section
+    BYTE,    // .byte
+    HALF,    // .half
+    SHORT,   // .short
+    WORD,    // .word
+    LONG,    // .long
+    DWORD,   // .dword
+    QUAD,    // .quad
+    ASCII,   // .ascii
+    ASCIZ,   // .asciz
+    STRING,  // .string
+    ALIGN,   // .align
+    BALIGN,  // .balign
+    P2ALIGN, // .p2align
+    SPACE,   // .space
+    SKIP,    // .skip
+    ZERO,    // .zero
+    FILL,    // .fill
+    ORG,     // .org
+    MEMORY,  // .memory
+    INCBIN,  // .incbin
+
+    // シンボル
+    GLOBL,  // .globl
+    GLOBAL, // .global
+    LOCAL,  // .local
+    EXTERN, // .extern
+    WEAK,   // .weak
+    SIZE,   // .size
+
+    // 条件アセンブル
+    IF,      // .if
+    IFDEF,   // .ifdef
+    IFNDEF,  // .ifndef
+    ELSEIF,  // .elseif
+    ELSE,    // .else
+    ENDIF,   // .endif
+    ERROR,   // .error
+    WARNING, // .warning
+
+    // 構造化制御
+    WHILE, // .while
+    ENDW,  // .endw
+    LOOP,  // .loop
+    ENDL,  // .endl
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self {
+            Self::NewLine => write!(f, "\\n"),
+            Self::Comma => write!(f, ","),
+            Self::Colon => write!(f, ":"),
+            Self::LParen => write!(f, "("),
+            Self::RParen => write!(f, ")"),
+            Self::Symbol(s) => write!(f, "{}", s),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Reloc(op) => write!(f, "%{}", op),
+            Self::Equal => write!(f, "="),
+            Self::Plus => write!(f, "+"),
+            Self::Minus => write!(f, "-"),
+            Self::Star => write!(f, "*"),
+            Self::Slash => write!(f, "/"),
+            Self::Percent => write!(f, "%"),
+            Self::Shl => write!(f, "<<"),
+            Self::Shr => write!(f, ">>"),
+            Self::Amp => write!(f, "&"),
+            Self::Pipe => write!(f, "|"),
+            Self::Caret => write!(f, "^"),
+            Self::Tilde => write!(f, "~"),
+            Self::At => write!(f, "@"),
+            Self::Dot => write!(f, "."),
+            Self::Bang => write!(f, "!"),
+            Self::EqEq => write!(f, "=="),
+            Self::Ne => write!(f, "!="),
+            Self::Lt => write!(f, "<"),
+            Self::Le => write!(f, "<="),
+            Self::Gt => write!(f, ">"),
+            Self::Ge => write!(f, ">="),
+            Self::AndAnd => write!(f, "&&"),
+            Self::OrOr => write!(f, "||"),
+            Self::Str(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            Self::EOF => write!(f, "EOF"),
+            Self::ILEGAL => write!(f, "ILEGAL"),
+            Self::Error(e) => write!(f, "{}", e),
+            // 予約語は対応する文字列を表示する
+            kind => match KEYWORDS.iter().find(|(_, k)| *k == kind) {
+                Some((ident, _)) => write!(f, "{}", ident),
+                None => write!(f, "{:?}", kind),
+            },
+        }
+    }
+}
+
+// マクロ展開の情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub name: String,
+    // マクロの呼び出し元の位置
+    pub call_site: Loc,
+}
+
+// token を読み取ったソースファイル
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: PathBuf,
+    // .include で読み込まれた場合は .include の位置を持つ
+    pub included_from: Option<Loc>,
+}
+
+impl SourceFile {
+    // SourceFileのコンストラクター
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            included_from: None,
+        }
+    }
+}
+
+// ソースコード上の位置
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Loc {
+    // ファイルから読み取った場合はそのファイル
+    pub file: Option<Rc<SourceFile>>,
+    pub line: usize,
+    pub col: usize,
+    // マクロ展開で生成された場合は展開の情報を持つ
+    pub expansion: Option<Box<Expansion>>,
+}
+
+// 表示するマクロ展開の数の上限
+// これより深い展開は、最も内側と最も外側の展開だけを表示して間を省略する
+const MAX_EXPANSION_FRAMES: usize = 8;
+
+impl Loc {
+    // マクロ展開の情報を除いた位置を表示する
+    fn fmt_pos(&self, f: &mut Formatter<'_>) -> Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.path.display())?;
+        }
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+impl Display for Loc {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.fmt_pos(f)?;
+
+        // 内側の展開から順に呼び出し元をたどる
+        let mut frames = vec![];
+        let mut expansion = self.expansion.as_deref();
+        while let Some(e) = expansion {
+            frames.push(e);
+            expansion = e.call_site.expansion.as_deref();
+        }
+        let omitted = match frames.len() {
+            n if n > MAX_EXPANSION_FRAMES => {
+                frames.drain(1..n - 1);
+                n - 2
+            }
+            _ => 0,
+        };
+
+        for (i, e) in frames.iter().enumerate() {
+            if i == 1 && omitted > 0 {
+                write!(f, " (... {} more ...", omitted)?;
+            }
+            write!(f, " (in macro {:?} called at ", e.name)?;
+            e.call_site.fmt_pos(f)?;
+        }
+        let depth = frames.len() + (omitted > 0) as usize;
+        write!(f, "{}", ")".repeat(depth))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub kind: TokenKind,
+    pub loc: Loc,
+}
+
+impl Token {
+    // Tokenのコンストラクター
+    pub fn new(kind: TokenKind) -> Self {
+        Self {
+            kind,
+            loc: Loc::default(),
+        }
+    }
 }
 
 // ident が予約後の場合は対応する TokenKind を返す
+// 命令とディレクティブは大文字と小文字を区別しない
 pub fn lookup_keyword(ident: &str) -> Option<TokenKind> {
-    if let Some(kind) = KEYWORDS.get(ident) {
+    if let Some(kind) = KEYWORDS.get(ident.to_ascii_lowercase().as_str()) {
         return Some(kind.clone());
     }
     None
 }
+
+// ident がレジスタ名の場合はレジスタ番号を返す
+// レジスタ名は大文字と小文字を区別しない
+pub fn lookup_register(ident: &str) -> Option<isize> {
+    REGISTERS.get(&ident.to_ascii_lowercase()).copied()
+}
@@ -0,0 +1,23 @@
+define i32 @"scale value"(i32 %x) {
+  %a = shl i32 %x, 3
+  %b = sub i32 %a, %x
+  %c = ashr i32 %b, 1
+  ret i32 %c
+}
+
+define i32 @clamp(i32 %x, i32 %lo, i32 %hi) {
+entry:
+  %s = call i32 @"scale value"(i32 %x)
+  %lt = icmp slt i32 %s, %lo
+  br i1 %lt, label %low, label %check
+check:
+  %gt = icmp sgt i32 %s, %hi
+  br i1 %gt, label %high, label %done
+low:
+  ret i32 %lo
+high:
+  %t = tail call i32 @"scale value"(i32 %hi)
+  ret i32 %t
+done:
+  ret i32 %s
+}
@@ -0,0 +1,59 @@
+	.text
+	.attribute	4, 16
+	.attribute	5, "rv32i2p0"
+	.file	"pic.ll"
+	.globl	"scale value"                   # -- Begin function scale value
+	.p2align	2
+	.type	"scale value",@function
+"scale value":                          # @"scale value"
+	.cfi_startproc
+# %bb.0:
+	slli	a1, a0, 3
+	sub	a0, a1, a0
+	srai	a0, a0, 1
+	ret
+.Lfunc_end0:
+	.size	"scale value", .Lfunc_end0-"scale value"
+	.cfi_endproc
+                                        # -- End function
+	.globl	clamp                           # -- Begin function clamp
+	.p2align	2
+	.type	clamp,@function
+clamp:                                  # @clamp
+	.cfi_startproc
+# %bb.0:                                # %entry
+	addi	sp, sp, -16
+	.cfi_def_cfa_offset 16
+	sw	ra, 12(sp)                      # 4-byte Folded Spill
+	sw	s0, 8(sp)                       # 4-byte Folded Spill
+	sw	s1, 4(sp)                       # 4-byte Folded Spill
+	.cfi_offset ra, -4
+	.cfi_offset s0, -8
+	.cfi_offset s1, -12
+	mv	s0, a2
+	mv	s1, a1
+	call	"scale value"@plt
+	bge	a0, s1, .LBB1_2
+# %bb.1:                                # %low
+	mv	a0, s1
+	j	.LBB1_4
+.LBB1_2:                                # %check
+	bge	s0, a0, .LBB1_4
+# %bb.3:                                # %high
+	mv	a0, s0
+	lw	ra, 12(sp)                      # 4-byte Folded Reload
+	lw	s0, 8(sp)                       # 4-byte Folded Reload
+	lw	s1, 4(sp)                       # 4-byte Folded Reload
+	addi	sp, sp, 16
+	tail	"scale value"@plt
+.LBB1_4:                                # %done
+	lw	ra, 12(sp)                      # 4-byte Folded Reload
+	lw	s0, 8(sp)                       # 4-byte Folded Reload
+	lw	s1, 4(sp)                       # 4-byte Folded Reload
+	addi	sp, sp, 16
+	ret
+.Lfunc_end1:
+	.size	clamp, .Lfunc_end1-clamp
+	.cfi_endproc
+                                        # -- End function
+	.section	".note.GNU-stack","",@progbits
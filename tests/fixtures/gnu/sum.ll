@@ -0,0 +1,33 @@
+define dso_local i32 @sum(i32* %p, i32 %n) {
+entry:
+  %cmp = icmp sgt i32 %n, 0
+  br i1 %cmp, label %loop, label %exit
+loop:
+  %i = phi i32 [ 0, %entry ], [ %i.next, %loop ]
+  %acc = phi i32 [ 0, %entry ], [ %acc.next, %loop ]
+  %ptr = getelementptr inbounds i32, i32* %p, i32 %i
+  %v = load i32, i32* %ptr, align 4
+  %acc.next = add i32 %acc, %v
+  %i.next = add i32 %i, 1
+  %done = icmp eq i32 %i.next, %n
+  br i1 %done, label %exit, label %loop
+exit:
+  %r = phi i32 [ 0, %entry ], [ %acc.next, %loop ]
+  ret i32 %r
+}
+
+define dso_local i32 @main() {
+entry:
+  %buf = alloca [4 x i32], align 4
+  %p0 = getelementptr inbounds [4 x i32], [4 x i32]* %buf, i32 0, i32 0
+  %p1 = getelementptr inbounds [4 x i32], [4 x i32]* %buf, i32 0, i32 1
+  %p2 = getelementptr inbounds [4 x i32], [4 x i32]* %buf, i32 0, i32 2
+  %p3 = getelementptr inbounds [4 x i32], [4 x i32]* %buf, i32 0, i32 3
+  store volatile i32 1, i32* %p0, align 4
+  store volatile i32 2, i32* %p1, align 4
+  store volatile i32 3, i32* %p2, align 4
+  store volatile i32 4, i32* %p3, align 4
+  %s = call i32 @sum(i32* %p0, i32 4)
+  %x = xor i32 %s, 305419896
+  ret i32 %x
+}
@@ -0,0 +1,58 @@
+	.text
+	.attribute	4, 16
+	.attribute	5, "rv32i2p0"
+	.file	"sum.ll"
+	.globl	sum                             # -- Begin function sum
+	.p2align	2
+	.type	sum,@function
+sum:                                    # @sum
+	.cfi_startproc
+# %bb.0:                                # %entry
+	li	a2, 0
+	blez	a1, .LBB0_2
+.LBB0_1:                                # %loop
+                                        # =>This Inner Loop Header: Depth=1
+	lw	a3, 0(a0)
+	add	a2, a2, a3
+	addi	a1, a1, -1
+	addi	a0, a0, 4
+	bnez	a1, .LBB0_1
+.LBB0_2:                                # %exit
+	mv	a0, a2
+	ret
+.Lfunc_end0:
+	.size	sum, .Lfunc_end0-sum
+	.cfi_endproc
+                                        # -- End function
+	.globl	main                            # -- Begin function main
+	.p2align	2
+	.type	main,@function
+main:                                   # @main
+	.cfi_startproc
+# %bb.0:                                # %entry
+	addi	sp, sp, -32
+	.cfi_def_cfa_offset 32
+	sw	ra, 28(sp)                      # 4-byte Folded Spill
+	.cfi_offset ra, -4
+	li	a0, 1
+	sw	a0, 12(sp)
+	li	a0, 2
+	sw	a0, 16(sp)
+	li	a0, 3
+	sw	a0, 20(sp)
+	li	a0, 4
+	sw	a0, 24(sp)
+	addi	a0, sp, 12
+	li	a1, 4
+	call	sum
+	lui	a1, 74565
+	addi	a1, a1, 1656
+	xor	a0, a0, a1
+	lw	ra, 28(sp)                      # 4-byte Folded Reload
+	addi	sp, sp, 32
+	ret
+.Lfunc_end1:
+	.size	main, .Lfunc_end1-main
+	.cfi_endproc
+                                        # -- End function
+	.section	".note.GNU-stack","",@progbits
@@ -0,0 +1,36 @@
+@counts = dso_local global [4 x i32] [i32 1, i32 -2, i32 3, i32 305419896], align 4
+@limits = dso_local constant [3 x i16] [i16 -1, i16 300, i16 7], align 2
+@flag = dso_local global i8 -3, align 1
+@greeting = dso_local constant [7 x i8] c"hi \E3\81\82\00", align 1
+@raw = dso_local constant [3 x i8] c"a\22\09", align 1
+
+define dso_local i32 @pick(i32 %x) {
+entry:
+  switch i32 %x, label %default [
+    i32 0, label %a
+    i32 1, label %b
+    i32 2, label %c
+    i32 3, label %d
+    i32 4, label %e
+  ]
+a:
+  ret i32 10
+b:
+  ret i32 27
+c:
+  ret i32 3
+d:
+  ret i32 91
+e:
+  ret i32 44
+default:
+  ret i32 0
+}
+
+define dso_local i32 @load(i32 %i) {
+entry:
+  %p = getelementptr inbounds [4 x i32], [4 x i32]* @counts, i32 0, i32 %i
+  %v = load i32, i32* %p, align 4
+  %r = add i32 %v, 1
+  ret i32 %r
+}
@@ -0,0 +1,109 @@
+	.text
+	.attribute	4, 16
+	.attribute	5, "rv32i2p0"
+	.file	"table.ll"
+	.globl	pick                            # -- Begin function pick
+	.p2align	2
+	.type	pick,@function
+pick:                                   # @pick
+	.cfi_startproc
+# %bb.0:                                # %entry
+	li	a1, 4
+	bltu	a1, a0, .LBB0_3
+# %bb.1:                                # %entry
+	slli	a0, a0, 2
+	lui	a1, %hi(.LJTI0_0)
+	addi	a1, a1, %lo(.LJTI0_0)
+	add	a0, a0, a1
+	lw	a0, 0(a0)
+	jr	a0
+.LBB0_2:                                # %a
+	li	a0, 10
+	ret
+.LBB0_3:                                # %default
+	li	a0, 0
+	ret
+.LBB0_4:                                # %b
+	li	a0, 27
+	ret
+.LBB0_5:                                # %c
+	li	a0, 3
+	ret
+.LBB0_6:                                # %d
+	li	a0, 91
+	ret
+.LBB0_7:                                # %e
+	li	a0, 44
+	ret
+.Lfunc_end0:
+	.size	pick, .Lfunc_end0-pick
+	.cfi_endproc
+	.section	.rodata,"a",@progbits
+	.p2align	2
+.LJTI0_0:
+	.word	.LBB0_2
+	.word	.LBB0_4
+	.word	.LBB0_5
+	.word	.LBB0_6
+	.word	.LBB0_7
+                                        # -- End function
+	.text
+	.globl	load                            # -- Begin function load
+	.p2align	2
+	.type	load,@function
+load:                                   # @load
+	.cfi_startproc
+# %bb.0:                                # %entry
+	lui	a1, %hi(counts)
+	addi	a1, a1, %lo(counts)
+	slli	a0, a0, 2
+	add	a0, a0, a1
+	lw	a0, 0(a0)
+	addi	a0, a0, 1
+	ret
+.Lfunc_end1:
+	.size	load, .Lfunc_end1-load
+	.cfi_endproc
+                                        # -- End function
+	.type	counts,@object                  # @counts
+	.data
+	.globl	counts
+	.p2align	2
+counts:
+	.word	1                               # 0x1
+	.word	4294967294                      # 0xfffffffe
+	.word	3                               # 0x3
+	.word	305419896                       # 0x12345678
+	.size	counts, 16
+
+	.type	limits,@object                  # @limits
+	.section	.rodata,"a",@progbits
+	.globl	limits
+	.p2align	1
+limits:
+	.half	65535                           # 0xffff
+	.half	300                             # 0x12c
+	.half	7                               # 0x7
+	.size	limits, 6
+
+	.type	flag,@object                    # @flag
+	.section	.sdata,"aw",@progbits
+	.globl	flag
+flag:
+	.byte	253                             # 0xfd
+	.size	flag, 1
+
+	.type	greeting,@object                # @greeting
+	.section	.rodata,"a",@progbits
+	.globl	greeting
+greeting:
+	.asciz	"hi \343\201\202"
+	.size	greeting, 7
+
+	.type	raw,@object                     # @raw
+	.globl	raw
+raw:
+	.ascii	"a\"\t"
+	.size	raw, 3
+
+	.section	".note.GNU-stack","",@progbits